@@ -1,12 +1,14 @@
 use log::{debug, info, trace};
 mod dmc;
 
-use self::dmc::{init_dmc, Ch5Register, DmcEvent, DmcWave};
+use self::dmc::{init_dmc, Ch5Register, DmcEvent, DmcReader, DmcWave};
 use bitflags::bitflags;
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::Duration;
 
+use crate::REGION;
+
 const MASTER_VOLUME: f32 = 0.4;
 
 pub struct NesAPU {
@@ -20,41 +22,86 @@ pub struct NesAPU {
     cycles: usize,
     counter: usize,
 
-    ch1_device: AudioDevice<SquareWave>,
+    ch1_device: Output<SquareWave>,
     ch1_sender: Sender<SquareEvent>,
     ch1_receiver: Receiver<ChannelEvent>,
     ch1_lenght_count: u32,
 
-    ch2_device: AudioDevice<SquareWave>,
+    ch2_device: Output<SquareWave>,
     ch2_sender: Sender<SquareEvent>,
     ch2_receiver: Receiver<ChannelEvent>,
     ch2_lenght_count: u32,
 
-    ch3_device: AudioDevice<TriangleWave>,
+    ch3_device: Output<TriangleWave>,
     ch3_sender: Sender<TriangleEvent>,
     ch3_receiver: Receiver<ChannelEvent>,
     ch3_lenght_count: u32,
 
-    ch4_device: AudioDevice<NoiseWave>,
+    ch4_device: Output<NoiseWave>,
     ch4_sender: Sender<NoiseEvent>,
     ch4_receiver: Receiver<ChannelEvent>,
     ch4_lenght_count: u32,
 
-    ch5_device: AudioDevice<DmcWave>,
+    ch5_device: Output<DmcWave>,
     ch5_sender: Sender<DmcEvent>,
     ch5_receiver: Receiver<ChannelEvent>,
-    ch5_lenght_count: u32,
+    dmc_reader: DmcReader,
+}
+
+// SDLのオーディオデバイス。SDLがないとき (テスト) は、デバイスを開かずに波形だけ持っておく。
+// どちらも持っておくだけ (dropすると音が止まり、チャンネルも切れる)
+#[allow(dead_code)]
+enum Output<T: AudioCallback> {
+    Device(AudioDevice<T>),
+    Headless(T),
 }
 
-const NES_CPU_CLOCK: f32 = 1_789_772.5; // 1.78MHz
+fn open_output<T, F>(sdl_context: Option<&sdl2::Sdl>, wave: F) -> Output<T>
+where
+    T: AudioCallback,
+    F: FnOnce(f32) -> T,
+{
+    let Some(sdl_context) = sdl_context else {
+        return Output::Headless(wave(44100.0));
+    };
+    let audio_subsystem = sdl_context.audio().unwrap();
+
+    let desired_spec = AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(1),
+        samples: Some(2),
+    };
+
+    let device = audio_subsystem
+        .open_playback(None, &desired_spec, |spec| wave(spec.freq as f32))
+        .unwrap();
+
+    device.resume();
+
+    Output::Device(device)
+}
+
+fn nes_cpu_clock() -> f32 {
+    unsafe { REGION.cpu_clock() }
+}
 
 impl NesAPU {
     pub fn new(sdl_context: &sdl2::Sdl) -> Self {
-        let (ch1_device, ch1_sender, ch1_receiver) = init_square(&sdl_context);
-        let (ch2_device, ch2_sender, ch2_receiver) = init_square(&sdl_context);
-        let (ch3_device, ch3_sender, ch3_receiver) = init_triangle(&sdl_context);
-        let (ch4_device, ch4_sender, ch4_receiver) = init_noise(&sdl_context);
-        let (ch5_device, ch5_sender, ch5_receiver) = init_dmc(&sdl_context);
+        Self::open(Some(sdl_context))
+    }
+
+    // 音を出さないAPU (テスト用)
+    #[cfg(test)]
+    pub fn headless() -> Self {
+        Self::open(None)
+    }
+
+    fn open(sdl_context: Option<&sdl2::Sdl>) -> Self {
+        let (ch1_device, ch1_sender, ch1_receiver) = init_square(sdl_context);
+        let (ch2_device, ch2_sender, ch2_receiver) = init_square(sdl_context);
+        let (ch3_device, ch3_sender, ch3_receiver) = init_triangle(sdl_context);
+        let (ch4_device, ch4_sender, ch4_receiver) = init_noise(sdl_context);
+        let (ch5_device, ch5_sender, ch5_receiver) = init_dmc(sdl_context);
 
         NesAPU {
             ch1_register: Ch1Register::new(),
@@ -90,7 +137,7 @@ impl NesAPU {
             ch5_device,
             ch5_sender,
             ch5_receiver,
-            ch5_lenght_count: 0,
+            dmc_reader: DmcReader::new(),
         }
     }
 
@@ -267,6 +314,7 @@ impl NesAPU {
         self.ch5_register.write(addr, value);
 
         if addr == 0x4010 {
+            self.dmc_reader.write_control(&self.ch5_register);
             self.ch5_sender
                 .send(DmcEvent::IrqEnable(self.ch5_register.irq_enabled))
                 .unwrap();
@@ -309,13 +357,23 @@ impl NesAPU {
         res = res | (if self.ch2_lenght_count == 0 { 0 } else { 1 } << 1);
         res = res | (if self.ch3_lenght_count == 0 { 0 } else { 1 } << 2);
         res = res | (if self.ch4_lenght_count == 0 { 0 } else { 1 } << 3);
-        res = res | (if self.ch5_lenght_count == 0 { 0 } else { 1 } << 4);
+        res = res | (if self.dmc_reader.is_active() { 1 } else { 0 } << 4);
+        res = res | (if self.dmc_reader.irq { 1 } else { 0 } << 7);
+        // 読むとフレーム割り込みは解除される (DMCの割り込みは残る)
         self.status.remove(StatusRegister::ENABLE_FRAME_IRQ);
         res
     }
 
     pub fn write_status(&mut self, data: u8) {
+        // 割り込みフラグは書き込みでは変わらない
+        let frame_irq = self.status.contains(StatusRegister::ENABLE_FRAME_IRQ);
         self.status.update(data);
+        self.status.set(StatusRegister::ENABLE_FRAME_IRQ, frame_irq);
+        self.status.remove(StatusRegister::ENABLE_DMC_IRQ);
+        self.dmc_reader.set_enabled(
+            self.status.contains(StatusRegister::ENABLE_5CH),
+            &self.ch5_register,
+        );
 
         self.ch1_sender
             .send(SquareEvent::Enable(
@@ -356,12 +414,59 @@ impl NesAPU {
         // }
     }
 
+    // 電源投入。全レジスタが0になる ($4017も0なので、フレーム割り込みは有効)
+    pub fn power_on(&mut self) {
+        for addr in 0x4000..=0x4003 {
+            self.write1ch(addr, 0);
+        }
+        for addr in 0x4004..=0x4007 {
+            self.write2ch(addr, 0);
+        }
+        for addr in [0x4008, 0x400A, 0x400B] {
+            self.write3ch(addr, 0);
+        }
+        for addr in [0x400C, 0x400E, 0x400F] {
+            self.write4ch(addr, 0);
+        }
+        for addr in 0x4010..=0x4013 {
+            self.write5ch(addr, 0);
+        }
+        self.dmc_reader = DmcReader::new();
+        self.write_status(0);
+        self.status.remove(StatusRegister::ENABLE_FRAME_IRQ);
+        self.write_frame_counter(0);
+    }
+
+    // リセット。全チャンネルが止まり ($4015 = 0)、$4017 は最後に書いた値がもう一度書かれる
+    pub fn reset(&mut self) {
+        self.write_status(0);
+        self.status.remove(StatusRegister::ENABLE_FRAME_IRQ);
+        self.write_frame_counter(self.frame_counter.bits());
+    }
+
+    pub fn dmc_dma_pending(&self) -> bool {
+        self.dmc_reader.needs_dma()
+    }
+
+    pub fn dmc_dma_addr(&self) -> u16 {
+        self.dmc_reader.dma_addr()
+    }
+
+    // 読んだ値はオーディオスレッドのDmcWaveが自分で読むので、ここでは進めるだけ
+    pub fn finish_dmc_dma(&mut self) {
+        self.dmc_reader.fetch(&self.ch5_register);
+    }
+
     pub fn irq(&self) -> bool {
-        self.status.contains(StatusRegister::ENABLE_FRAME_IRQ)
+        self.status.contains(StatusRegister::ENABLE_FRAME_IRQ) || self.dmc_reader.irq
     }
 
     pub fn write_frame_counter(&mut self, value: u8) {
         self.frame_counter.update(value);
+        if !self.frame_counter.irq() {
+            // 割り込み禁止にすると、立っているフレーム割り込みも解除される
+            self.status.remove(StatusRegister::ENABLE_FRAME_IRQ);
+        }
         self.cycles = 0;
         self.counter = 0;
     }
@@ -398,7 +503,8 @@ impl NesAPU {
         loop {
             let res = self.ch5_receiver.recv_timeout(Duration::from_millis(0));
             match res {
-                Ok(ChannelEvent::LengthCounter(count)) => self.ch5_lenght_count = count,
+                // DMCの状態はdmc_readerで数えているので、読み捨てる
+                Ok(ChannelEvent::LengthCounter(_)) => {}
                 _ => break,
             }
         }
@@ -406,8 +512,11 @@ impl NesAPU {
 
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
+        for _ in 0..cycles {
+            self.dmc_reader.tick(&self.ch5_register);
+        }
 
-        let interval = 7457;
+        let interval = unsafe { REGION.frame_counter_interval() };
         if self.cycles >= interval {
             self.cycles -= interval;
             self.counter += 1;
@@ -933,7 +1042,7 @@ impl Sweep {
         if self.frequency == 0 {
             return 0.0;
         }
-        NES_CPU_CLOCK / (16.0 * (self.frequency as f32 + 1.0))
+        nes_cpu_clock() / (16.0 * (self.frequency as f32 + 1.0))
     }
 
     fn reset(&mut self) {
@@ -1055,40 +1164,28 @@ impl AudioCallback for SquareWave {
 }
 
 fn init_square(
-    sdl_context: &sdl2::Sdl,
+    sdl_context: Option<&sdl2::Sdl>,
 ) -> (
-    AudioDevice<SquareWave>,
+    Output<SquareWave>,
     Sender<SquareEvent>,
     Receiver<ChannelEvent>,
 ) {
-    let audio_subsystem = sdl_context.audio().unwrap();
-
     let (sender, receiver) = channel::<SquareEvent>();
     let (sender2, receiver2) = channel::<ChannelEvent>();
 
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1),
-        samples: Some(2),
-    };
-
-    let device = audio_subsystem
-        .open_playback(None, &desired_spec, |spec| SquareWave {
-            freq: spec.freq as f32,
-            phase: 0.0,
-            receiver: receiver,
-            sender: sender2,
-            enabled: true,
-            note: SquareNote::new(),
-            envelope: Envelope::new(),
-            length_counter: LengthCounter::new(),
-            sweep: Sweep::new(),
-        })
-        .unwrap();
-
-    device.resume();
-
-    (device, sender, receiver2)
+    let output = open_output(sdl_context, |freq| SquareWave {
+        freq: freq,
+        phase: 0.0,
+        receiver: receiver,
+        sender: sender2,
+        enabled: true,
+        note: SquareNote::new(),
+        envelope: Envelope::new(),
+        length_counter: LengthCounter::new(),
+        sweep: Sweep::new(),
+    });
+
+    (output, sender, receiver2)
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -1112,7 +1209,7 @@ impl TriangleNote {
     }
 
     fn hz(&self) -> f32 {
-        NES_CPU_CLOCK / (32.0 * (self.frequency as f32 + 1.0))
+        nes_cpu_clock() / (32.0 * (self.frequency as f32 + 1.0))
     }
 }
 
@@ -1184,46 +1281,29 @@ impl AudioCallback for TriangleWave {
 }
 
 fn init_triangle(
-    sdl_context: &sdl2::Sdl,
+    sdl_context: Option<&sdl2::Sdl>,
 ) -> (
-    AudioDevice<TriangleWave>,
+    Output<TriangleWave>,
     Sender<TriangleEvent>,
     Receiver<ChannelEvent>,
 ) {
-    let audio_subsystem = sdl_context.audio().unwrap();
-
     let (sender, receiver) = channel::<TriangleEvent>();
     let (sender2, receiver2) = channel::<ChannelEvent>();
 
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1),
-        samples: Some(2),
-    };
-
-    let device = audio_subsystem
-        .open_playback(None, &desired_spec, |spec| TriangleWave {
-            freq: spec.freq as f32,
-            phase: 0.0,
-            receiver: receiver,
-            sender: sender2,
-            enabled: true,
-            note: TriangleNote::new(),
-            length_counter: LengthCounter::new(),
-            linear_counter: LinearCounter::new(),
-        })
-        .unwrap();
-
-    device.resume();
-
-    (device, sender, receiver2)
+    let output = open_output(sdl_context, |freq| TriangleWave {
+        freq: freq,
+        phase: 0.0,
+        receiver: receiver,
+        sender: sender2,
+        enabled: true,
+        note: TriangleNote::new(),
+        length_counter: LengthCounter::new(),
+        linear_counter: LinearCounter::new(),
+    });
+
+    (output, sender, receiver2)
 }
 
-static NOISE_TABLE: [u16; 16] = [
-    0x004, 0x008, 0x010, 0x020, 0x040, 0x060, 0x080, 0x0A0, 0x0CA, 0x0FE, 0x17C, 0x1FC, 0x2FA,
-    0x3F8, 0x7F2, 0xFE4,
-];
-
 #[derive(Debug, Clone, PartialEq)]
 enum NoiseEvent {
     Note(NoiseNote),
@@ -1249,7 +1329,7 @@ impl NoiseNote {
     }
 
     fn hz(&self) -> f32 {
-        NES_CPU_CLOCK / NOISE_TABLE[self.frequency as usize] as f32
+        nes_cpu_clock() / unsafe { REGION.noise_period(self.frequency) } as f32
     }
 
     fn is_long(&self) -> bool {
@@ -1354,41 +1434,29 @@ impl NoiseRandom {
 }
 
 fn init_noise(
-    sdl_context: &sdl2::Sdl,
+    sdl_context: Option<&sdl2::Sdl>,
 ) -> (
-    AudioDevice<NoiseWave>,
+    Output<NoiseWave>,
     Sender<NoiseEvent>,
     Receiver<ChannelEvent>,
 ) {
-    let audio_subsystem = sdl_context.audio().unwrap();
-
     let (sender, receiver) = channel::<NoiseEvent>();
     let (sender2, receiver2) = channel::<ChannelEvent>();
 
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1),
-        samples: Some(2),
-    };
-
-    let device = audio_subsystem
-        .open_playback(None, &desired_spec, |spec| NoiseWave {
-            freq: spec.freq as f32,
-            phase: 0.0,
-            receiver: receiver,
-            sender: sender2,
-            random: NoiseRandom::new(),
-            enabled: true,
-            envelope: Envelope::new(),
-            note: NoiseNote::new(),
-            length_counter: LengthCounter::new(),
-            value: false,
-        })
-        .unwrap();
-
-    device.resume();
-
-    (device, sender, receiver2)
+    let output = open_output(sdl_context, |freq| NoiseWave {
+        freq: freq,
+        phase: 0.0,
+        receiver: receiver,
+        sender: sender2,
+        random: NoiseRandom::new(),
+        enabled: true,
+        envelope: Envelope::new(),
+        note: NoiseNote::new(),
+        length_counter: LengthCounter::new(),
+        value: false,
+    });
+
+    (output, sender, receiver2)
 }
 
 bitflags! {
@@ -3,16 +3,15 @@ use std::{
     time::Duration,
 };
 
-use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::audio::AudioCallback;
 
-use crate::MAPPER;
+use crate::{MAPPER, REGION};
 
-use super::{ChannelEvent, MASTER_VOLUME, NES_CPU_CLOCK};
+use super::{nes_cpu_clock, open_output, ChannelEvent, Output, MASTER_VOLUME};
 
-static FREQUENCY_TABLE: [u16; 16] = [
-    0x1AC, 0x17C, 0x154, 0x140, 0x11E, 0x0FE, 0x0E2, 0x0D6, 0x0BE, 0x0A0, 0x08E, 0x080, 0x06A,
-    0x054, 0x048, 0x036,
-];
+fn frequency(index: u8) -> f32 {
+    nes_cpu_clock() / unsafe { REGION.dmc_period(index) } as f32
+}
 
 pub struct Ch5Register {
     // 4010
@@ -63,6 +62,100 @@ impl Ch5Register {
     }
 }
 
+// CPU側で数える、DMCのサンプル読み込みのタイミング。
+// 音はオーディオスレッドのDmcWaveが鳴らすが、IRQと$4015の状態はCPUのサイクルに合わせる必要がある。
+// see: https://www.nesdev.org/wiki/APU_DMC
+pub struct DmcReader {
+    timer: u16,
+    bits_remaining: u8,
+    buffer_empty: bool,
+    current_addr: u16,
+    bytes_remaining: u16,
+    pub irq: bool,
+}
+
+impl DmcReader {
+    pub fn new() -> Self {
+        DmcReader {
+            timer: 0,
+            bits_remaining: 8,
+            buffer_empty: true,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            irq: false,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // $4015 の bit4
+    pub fn set_enabled(&mut self, enabled: bool, register: &Ch5Register) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart(register);
+        }
+    }
+
+    // $4010
+    pub fn write_control(&mut self, register: &Ch5Register) {
+        if !register.irq_enabled {
+            self.irq = false;
+        }
+    }
+
+    fn restart(&mut self, register: &Ch5Register) {
+        self.current_addr = 0xC000 + register.start_addr as u16 * 0x40;
+        self.bytes_remaining = register.byte_count as u16 * 0x10 + 1;
+    }
+
+    // バッファが空になったら、DMAでサンプルを読んでもらう
+    pub fn needs_dma(&self) -> bool {
+        self.buffer_empty && self.bytes_remaining > 0
+    }
+
+    pub fn dma_addr(&self) -> u16 {
+        self.current_addr
+    }
+
+    // 1CPUサイクル分進める
+    pub fn tick(&mut self, register: &Ch5Register) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = unsafe { REGION.dmc_period(register.frequency_index) } - 1;
+
+        // 出力ユニットが8bit使い切ったら、バッファの次のバイトに移る
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            self.buffer_empty = true;
+        }
+    }
+
+    // DMAで1バイト読み終わった
+    pub fn fetch(&mut self, register: &Ch5Register) {
+        self.buffer_empty = false;
+        self.current_addr = if self.current_addr == 0xFFFF {
+            0x8000
+        } else {
+            self.current_addr + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if register.loop_flag {
+                self.restart(register);
+            } else if register.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum DmcEvent {
     IrqEnable(bool),
@@ -113,7 +206,7 @@ impl AudioCallback for DmcWave {
                     }
                     Ok(DmcEvent::Frequency(f)) => {
                         self.frequency_index = f;
-                        self.frequency = NES_CPU_CLOCK / FREQUENCY_TABLE[f as usize] as f32;
+                        self.frequency = frequency(f);
                     }
                     Ok(DmcEvent::Delta(d)) => {
                         self.delta_counter = d;
@@ -206,44 +299,28 @@ impl DmcWave {
 }
 
 pub fn init_dmc(
-    sdl_context: &sdl2::Sdl,
-) -> (
-    AudioDevice<DmcWave>,
-    Sender<DmcEvent>,
-    Receiver<ChannelEvent>,
-) {
-    let audio_subsystem = sdl_context.audio().unwrap();
-
+    sdl_context: Option<&sdl2::Sdl>,
+) -> (Output<DmcWave>, Sender<DmcEvent>, Receiver<ChannelEvent>) {
     let (sender, receiver) = channel::<DmcEvent>();
     let (sender2, receiver2) = channel::<ChannelEvent>();
 
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1),
-        samples: Some(2),
-    };
-
-    let device = audio_subsystem
-        .open_playback(None, &desired_spec, |spec| DmcWave {
-            freq: spec.freq as f32,
-            phase: 0.0,
-            receiver: receiver,
-            sender: sender2,
-            enabled: true,
-            irq_enabled: false,
-            loop_flag: false,
-            frequency_index: 0,
-            delta_counter: 0,
-            start_addr: 0,
-            byte_count: 1,
-            data: 0,
-            frequency: NES_CPU_CLOCK / FREQUENCY_TABLE[0] as f32,
-            sample_addr: 0xC000,
-            counter: (0 * 8) as u32 * 0x10 + 1,
-        })
-        .unwrap();
-
-    device.resume();
+    let output = open_output(sdl_context, |freq| DmcWave {
+        freq: freq,
+        phase: 0.0,
+        receiver: receiver,
+        sender: sender2,
+        enabled: true,
+        irq_enabled: false,
+        loop_flag: false,
+        frequency_index: 0,
+        delta_counter: 0,
+        start_addr: 0,
+        byte_count: 1,
+        data: 0,
+        frequency: frequency(0),
+        sample_addr: 0xC000,
+        counter: (0 * 8) as u32 * 0x10 + 1,
+    });
 
-    (device, sender, receiver2)
+    (output, sender, receiver2)
 }
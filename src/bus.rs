@@ -1,37 +1,107 @@
+use crate::cpu::IN_TRACE;
 use crate::frame::Frame;
-use crate::joypad::Joypad;
-use crate::mapper::Mapper;
+use crate::joypad::{InputDevice, Joypad};
+use crate::mapper::{create_mapper, Mapper};
 use crate::ppu::NesPPU;
-use crate::{apu::NesAPU, MAPPER};
+use crate::rom::Rom;
+use crate::{apu::NesAPU, MAPPER, RAM_INIT, REGION};
 use log::{debug, error, info, log_enabled, trace, warn, Level};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// 電源投入時のRAMの中身 (CPUのRAM, PPUのVRAM, OAM, パレット)
+// 実機では不定なので、初期化していないメモリに頼るゲームを見つけるのに使う
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RamInit {
+    Zeros,
+    Ones,
+    Random(u64),
+    // 実機でよく見られる、$00と$FFが4バイトずつ交互に並ぶパターン
+    Console,
+}
+
+impl RamInit {
+    // zeros, ff, random, random:<seed>, console
+    pub fn parse(name: &str) -> Option<RamInit> {
+        match name {
+            "zeros" => Some(RamInit::Zeros),
+            "ff" => Some(RamInit::Ones),
+            "random" => Some(RamInit::Random(rand::random())),
+            "console" => Some(RamInit::Console),
+            _ => name
+                .strip_prefix("random:")
+                .and_then(|seed| seed.parse().ok())
+                .map(|seed| RamInit::Random(seed)),
+        }
+    }
+
+    pub fn fill(&self, ram: &mut [u8]) {
+        match self {
+            RamInit::Zeros => ram.fill(0x00),
+            RamInit::Ones => ram.fill(0xFF),
+            RamInit::Random(seed) => StdRng::seed_from_u64(*seed).fill(ram),
+            RamInit::Console => {
+                for (i, value) in ram.iter_mut().enumerate() {
+                    *value = if i & 0x04 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+        }
+    }
+}
+
+// フロントエンドから本体への操作
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetKind {
+    // リセットボタン
+    Soft,
+    // 電源の入れ直し
+    PowerCycle,
+}
 
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
     // prg_rom: Vec<u8>,
     ppu: NesPPU,
     frame: Frame,
-    joypad1: Joypad,
-    joypad2: Joypad,
+    // コントローラーポート (1P, 2P)
+    ports: [Box<dyn InputDevice>; 2],
     apu: NesAPU,
+    // 最後にデータバスに乗った値。何も出力しないビットはこの値が読める
+    open_bus: u8,
 
     cycles: usize,
-    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad, &Frame) + 'call>,
+    // $4014 に書かれたページ。次のCPUの読み込みサイクルでDMAを始める
+    oam_dma: Option<u8>,
+    // PALのPPUはCPUの3.2倍なので、端数を持っておく
+    ppu_dots_remainder: usize,
+    reset_request: Option<ResetKind>,
+    // 差し込まれたカートリッジ。電源を入れるたびにマッパーを作り直す
+    cartridge: Option<Rom>,
+    gameloop_callback: Box<
+        dyn FnMut(&NesPPU, &mut [Box<dyn InputDevice>; 2], &Frame) -> Option<ResetKind> + 'call,
+    >,
 }
 
 impl<'a> Bus<'a> {
     pub fn new<'call, F>(apu: NesAPU, gameloop_callback: F) -> Bus<'call>
     where
-        F: FnMut(&NesPPU, &mut Joypad, &Frame) + 'call,
+        F: FnMut(&NesPPU, &mut [Box<dyn InputDevice>; 2], &Frame) -> Option<ResetKind> + 'call,
     {
         let ppu = NesPPU::new();
+        let mut cpu_vram = [0; 2048];
+        unsafe { RAM_INIT.fill(&mut cpu_vram) };
         Bus {
-            cpu_vram: [0; 2048],
+            cpu_vram: cpu_vram,
             ppu: ppu,
             frame: Frame::new(),
-            joypad1: Joypad::new(),
-            joypad2: Joypad::new(),
+            ports: [Box::new(Joypad::new()), Box::new(Joypad::new())],
             apu: apu,
+            open_bus: 0,
             cycles: 0,
+            oam_dma: None,
+            ppu_dots_remainder: 0,
+            reset_request: None,
+            cartridge: None,
             gameloop_callback: Box::from(gameloop_callback),
         }
     }
@@ -39,30 +109,101 @@ impl<'a> Bus<'a> {
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
 
-        let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles * 3, &mut self.frame);
-        let nmi_after = self.ppu.nmi_interrupt.is_some();
+        let (num, den) = unsafe { REGION.ppu_dots_per_cpu_cycle() };
+        let dots = cycles as usize * num + self.ppu_dots_remainder;
+        self.ppu_dots_remainder = dots % den;
+        let frame_end = self.ppu.tick((dots / den) as u8, &mut self.frame);
 
         self.apu.tick(cycles);
+        unsafe { MAPPER.tick(cycles) };
 
-        if !nmi_before && nmi_after {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad1, &self.frame);
+        if frame_end {
+            if let Some(kind) = (self.gameloop_callback)(&self.ppu, &mut self.ports, &self.frame) {
+                self.reset_request = Some(kind);
+            }
         }
     }
 
-    pub fn poll_nmi_status(&mut self) -> Option<i32> {
-        if self.ppu.clear_nmi_interrupt {
-            self.ppu.clear_nmi_interrupt = false;
-            self.ppu.nmi_interrupt = None;
-            return None;
+    pub fn set_remove_sprite_limit(&mut self, value: bool) {
+        self.ppu.remove_sprite_limit = value;
+    }
+
+    // ポート (0: 1P, 1: 2P) に機器をつなぐ
+    pub fn connect(&mut self, port: usize, device: Box<dyn InputDevice>) {
+        self.ports[port] = device;
+    }
+
+    pub fn insert_cartridge(&mut self, rom: Rom) {
+        self.cartridge = Some(rom);
+    }
+
+    pub fn take_reset_request(&mut self) -> Option<ResetKind> {
+        self.reset_request.take()
+    }
+
+    // 電源投入。RAMは電源投入時の中身になる
+    pub fn power_on(&mut self) {
+        if let Some(rom) = &self.cartridge {
+            // バッテリーバックアップのRAMは、セーブファイルから読み直す
+            let mut rom = rom.clone();
+            if !rom.save_data_file.is_empty() {
+                rom.save_data = std::fs::read(&rom.save_data_file).unwrap_or_default();
+            }
+            unsafe { *MAPPER = create_mapper(rom) };
         }
-        let res = self.ppu.nmi_interrupt;
-        self.ppu.nmi_interrupt = None;
-        res
+        unsafe { RAM_INIT.fill(&mut self.cpu_vram) };
+        self.ppu.power_on();
+        self.apu.power_on();
+        self.oam_dma = None;
+        self.ppu_dots_remainder = 0;
+    }
+
+    // リセットボタン。RAMはそのまま残る。
+    // カートリッジにはリセット信号が来ないので、マッパーもそのまま。
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.oam_dma = None;
+    }
+
+    pub fn dma_pending(&self) -> bool {
+        self.oam_dma.is_some() || self.apu.dmc_dma_pending()
+    }
+
+    pub fn take_oam_dma(&mut self) -> Option<u8> {
+        self.oam_dma.take()
     }
 
-    pub fn poll_apu_irq(&mut self) -> bool {
-        self.apu.irq()
+    pub fn dmc_dma_pending(&self) -> bool {
+        self.apu.dmc_dma_pending()
+    }
+
+    // DMCのサンプルを1バイト読む
+    pub fn dmc_dma_read(&mut self) {
+        let addr = self.apu.dmc_dma_addr();
+        self.mem_read(addr);
+        self.apu.finish_dmc_dma();
+    }
+
+    // DMAは、CPUサイクルの読み込み側(get)と書き込み側(put)を交互に使う
+    pub fn is_get_cycle(&self) -> bool {
+        self.cycles % 2 == 0
+    }
+
+    // PPUのNMI出力 (レベル)
+    pub fn nmi_line(&mut self) -> bool {
+        self.ppu.nmi_line()
+    }
+
+    // IRQはワイヤードOR。要因(APUのフレームカウンタ、DMC、マッパー)ごとに、
+    // CPUが原因を解除するまで立ちっぱなしになる。
+    pub fn irq_line(&mut self) -> bool {
+        self.apu.irq() || unsafe { MAPPER.is_irq() }
+    }
+
+    #[cfg(test)]
+    pub fn ppu(&mut self) -> &mut NesPPU {
+        &mut self.ppu
     }
 }
 
@@ -81,7 +222,7 @@ pub trait Mem {
 
 impl Mem for Bus<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
-        match addr {
+        let value = match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b_0000_0111_1111_1111;
                 let v = self.cpu_vram[mirror_down_addr as usize];
@@ -93,12 +234,12 @@ impl Mem for Bus<'_> {
                 );
                 v
             }
-            0x2003 | 0x2005 | 0x2006 | 0x4014 => {
-                warn!("Attempt to read from write-only PPU address {:X}", addr);
-                0
+            0x4014 => {
+                warn!("Attempt to read from write-only address {:X}", addr);
+                self.open_bus
             }
-            0x2000 => self.ppu.read_ctrl(),
-            0x2001 => self.ppu.read_mask(),
+            // 書き込み専用のレジスタはオープンバス
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 => self.ppu.read_open_bus(),
             0x2002 => self.ppu.read_status(),
             0x2004 => self.ppu.read_oam_data(),
             0x2007 => self.ppu.read_data(),
@@ -108,22 +249,31 @@ impl Mem for Bus<'_> {
                 self.mem_read(mirror_down_addr)
             }
             0x4015 => self.apu.read_status(),
-            0x4016 => self.joypad1.read(),
-            0x4017 => {
-                // readはjoypad2になるらしいが、writeはAPUらしい。。
-                // self.joypad2.read()
-                0
-            }
-            0x6000..=0x7FFF => unsafe { MAPPER.read_prg_ram(addr) },
+            // 読み込みはコントローラー、上位3bitはオープンバス (LDA $4016 なら$40)
+            // see: https://www.nesdev.org/wiki/Standard_controller
+            0x4016 => (self.ports[0].read() & 0x1F) | (self.open_bus & 0xE0),
+            0x4017 => (self.ports[1].read() & 0x1F) | (self.open_bus & 0xE0),
+            0x6000..=0x7FFF => unsafe {
+                if MAPPER.is_prg_ram_open_bus(addr) {
+                    self.open_bus
+                } else {
+                    MAPPER.read_prg_ram(addr)
+                }
+            },
             PRG_ROM..=PRG_ROM_END => unsafe { MAPPER.read_prg_rom(addr) },
             _ => {
                 warn!("Ignoreing mem access at {:X}", addr);
-                0
+                self.open_bus
             }
+        };
+        if !unsafe { IN_TRACE } {
+            self.open_bus = value;
         }
+        value
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b_0000_0111_1111_1111;
@@ -172,12 +322,12 @@ impl Mem for Bus<'_> {
                 self.apu.write_status(data);
             }
             0x4016 => {
-                self.joypad1.write(data);
-                // TODO 2Pの書き込みもここでやるのかな？
-                // self.joypad2.write(data);
+                // ストローブは両方のポートに届く
+                for port in self.ports.iter_mut() {
+                    port.write(data);
+                }
             }
             0x4017 => {
-                // self.joypad2.write(data);
                 // 書き込みは、joypadではなく、APUになる。
                 //   APUフレームカウンター
                 //   https://www.nesdev.org/wiki/APU_Frame_Counter
@@ -185,16 +335,11 @@ impl Mem for Bus<'_> {
             }
             0x4014 => {
                 // $XX を書き込むと、256 バイトのデータが CPU ページ $XX00 ～ $XXFF から内部 PPU OAM にアップロードされます。このページは通常、内部 RAM (通常は $0200 ～ $02FF) にありますが、カートリッジ RAM または ROM も使用できます。
-                let mut values: [u8; 256] = [0; 256];
-                for i in 0x00..=0xFF {
-                    values[i] = self.mem_read((data as u16) << 8 | i as u16);
-                }
-                self.ppu.write_to_oam_dma(values);
                 // Not counting the OAMDMA write tick, the above procedure takes 513 CPU cycles (+1 on odd CPU cycles)
-                for _ in 0..513 {
-                    self.ppu.tick(1, &mut self.frame);
-                }
+                // => 転送中はCPUが止まるので、CPU側 (CPU::run_dma) で行う
+                self.oam_dma = Some(data);
             }
+            0x4020..=0x5FFF => unsafe { MAPPER.write_expansion(addr, data) },
             0x6000..=0x7FFF => unsafe { MAPPER.write_prg_ram(addr, data) },
             PRG_ROM..=PRG_ROM_END => {
                 unsafe { MAPPER.write(addr, data) };
@@ -205,3 +350,46 @@ impl Mem for Bus<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::insert_mapper;
+
+    fn test_bus<'a>() -> Bus<'a> {
+        Bus::new(NesAPU::headless(), |_, _, _| None)
+    }
+
+    #[test]
+    fn test_ppu_write_only_registers_read_latch() {
+        let _mapper = insert_mapper(Rom::empty());
+        let mut bus = test_bus();
+        bus.mem_write(0x2000, 0x5A);
+        for addr in [0x2000, 0x2001, 0x2003, 0x2005, 0x2006, 0x3FF8] {
+            assert_eq!(bus.mem_read(addr), 0x5A, "{:04X}", addr);
+        }
+    }
+
+    #[test]
+    fn test_controller_port_open_bus() {
+        let _mapper = insert_mapper(Rom::empty());
+        let mut bus = test_bus();
+        // 上位3bitは、直前にバスに乗った値
+        bus.mem_write(0x0000, 0xFF);
+        assert_eq!(bus.mem_read(0x4016), 0xE0);
+        bus.mem_write(0x0000, 0x40);
+        assert_eq!(bus.mem_read(0x4017), 0x40);
+    }
+
+    #[test]
+    fn test_unmapped_read_open_bus() {
+        let _mapper = insert_mapper(Rom::empty());
+        let mut bus = test_bus();
+        bus.mem_write(0x0000, 0x37);
+        assert_eq!(bus.mem_read(0x5000), 0x37);
+        assert_eq!(bus.mem_read(0x4014), 0x37);
+        assert_eq!(bus.mem_read(0x0000), 0x37);
+        bus.mem_write(0x0001, 0x12);
+        assert_eq!(bus.mem_read(0x5000), 0x12);
+    }
+}
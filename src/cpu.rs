@@ -1,9 +1,10 @@
-use log::{debug, info, trace};
+use log::{debug, error, info, trace};
 
-use crate::opscodes::{call, CPU_OPS_CODES};
-use crate::MAPPER;
+use crate::opscodes::{call, instruction, CPU_OPS_CODES, CPU_OPS_TABLE};
 
-use crate::bus::{Bus, Mem};
+use crate::bus::{Bus, Mem, ResetKind};
+use crate::rom::Region;
+use crate::REGION;
 
 #[derive(Debug, Clone, PartialEq)]
 #[allow(non_camel_case_types)]
@@ -32,6 +33,8 @@ pub enum CycleCalcMode {
     Branch,
 }
 
+pub type Instruction = fn(&mut CPU, &AddressingMode);
+
 #[derive(Debug, Clone)]
 pub struct OpCode {
     pub code: u8,
@@ -40,6 +43,7 @@ pub struct OpCode {
     pub cycles: u8,
     pub cycle_calc_mode: CycleCalcMode,
     pub addressing_mode: AddressingMode,
+    pub instruction: Instruction,
 }
 
 impl OpCode {
@@ -58,6 +62,7 @@ impl OpCode {
             cycles: cycles,
             cycle_calc_mode: cycle_calc_mode,
             addressing_mode: addressing_mode,
+            instruction: instruction(name),
         }
     }
 }
@@ -73,6 +78,9 @@ const FLAG_NEGATIVE: u8 = 1 << 7;
 
 const SIGN_BIT: u8 = 1 << 7;
 
+// ANE/LXA の不安定な定数。個体や温度で変わるが、よく使われる値にしておく。
+const UNSTABLE_MAGIC: u8 = 0xEE;
+
 pub struct CPU<'a> {
     pub register_a: u8,
     pub register_x: u8,
@@ -83,18 +91,44 @@ pub struct CPU<'a> {
     // pub memory: [u8; 0x10000], // 0xFFFF
     pub bus: Bus<'a>,
 
-    add_cycles: u8,
+    // 実行中の命令のサイクル計算方法。インデックス付きアドレスの空読みに使う
+    cycle_calc_mode: CycleCalcMode,
+    // JAM で止まっている
+    pub halted: bool,
+
+    // 割り込みの検出。NMIは立ち上がり(エッジ)、IRQはレベルで、サイクルごとに見る。
+    // 命令の最後のサイクルの1つ前までに検出したものが、命令の後に実行されるので、1サイクル前の値も持っておく。
+    // see: https://www.nesdev.org/wiki/CPU_interrupts
+    nmi_line: bool,
+    need_nmi: bool,
+    prev_need_nmi: bool,
+    run_irq: bool,
+    prev_run_irq: bool,
 }
 
 pub static mut IN_TRACE: bool = false;
 
+// CPUのバスアクセスは1回で1サイクル。
+// アクセスの前にPPU/APUを進めておくので、命令の途中のレジスタの読み書きも正しいタイミングになる。
 impl Mem for CPU<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
-        self.bus.mem_read(addr)
+        if unsafe { IN_TRACE } {
+            return self.bus.mem_read(addr);
+        }
+        // DMAは読み込みサイクルでしかCPUを止められない
+        if self.bus.dma_pending() {
+            self.run_dma(addr);
+        }
+        self.bus.tick(1);
+        let value = self.bus.mem_read(addr);
+        self.poll_interrupts();
+        value
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
-        self.bus.mem_write(addr, data)
+        self.bus.tick(1);
+        self.bus.mem_write(addr, data);
+        self.poll_interrupts();
     }
 }
 
@@ -104,12 +138,18 @@ impl<'a> CPU<'a> {
             register_a: 0,
             register_x: 0,
             register_y: 0,
-            status: FLAG_INTERRRUPT | FLAG_BREAK2, // FIXME あってる？
+            status: FLAG_INTERRRUPT | FLAG_BREAK2,
             program_counter: 0,
-            stack_pointer: 0xFD, // FIXME あってる？
+            stack_pointer: 0xFD,
             // memory: [0x00; 0x10000],
             bus: bus,
-            add_cycles: 0,
+            cycle_calc_mode: CycleCalcMode::None,
+            halted: false,
+            nmi_line: false,
+            need_nmi: false,
+            prev_need_nmi: false,
+            run_irq: false,
+            prev_run_irq: false,
         }
     }
 
@@ -133,6 +173,8 @@ impl<'a> CPU<'a> {
             // LDA $44,X => b5 44
             AddressingMode::ZeroPage_X => {
                 let pos = self.mem_read(self.program_counter);
+                // Xを足す前のアドレスを空読みする
+                self.mem_read(pos as u16);
                 let addr = pos.wrapping_add(self.register_x) as u16;
                 addr
             }
@@ -140,6 +182,7 @@ impl<'a> CPU<'a> {
             // LDX $44,Y => b6 44
             AddressingMode::ZeroPage_Y => {
                 let pos = self.mem_read(self.program_counter);
+                self.mem_read(pos as u16);
                 let addr = pos.wrapping_add(self.register_y) as u16;
                 addr
             }
@@ -149,9 +192,7 @@ impl<'a> CPU<'a> {
                 let base = self.mem_read_u16(self.program_counter);
                 let addr = base.wrapping_add(self.register_x as u16);
                 // (+1 if page crossed)
-                if base & 0xFF00 != addr & 0xFF00 {
-                    self.add_cycles += 1;
-                }
+                self.dummy_read_indexed(base, addr);
                 addr
             }
 
@@ -160,35 +201,33 @@ impl<'a> CPU<'a> {
                 let base = self.mem_read_u16(self.program_counter);
                 let addr = base.wrapping_add(self.register_y as u16);
                 // (+1 if page crossed)
-                if base & 0xFF00 != addr & 0xFF00 {
-                    self.add_cycles += 1;
-                }
+                self.dummy_read_indexed(base, addr);
                 addr
             }
             // JMP -> same Absolute
             AddressingMode::Indirect => {
                 let base = self.mem_read_u16(self.program_counter);
-                let addr = self.mem_read_u16(base);
-                addr
+                // $xxFF の場合、上位バイトは $xx00 から読む (6502のバグ)
+                let lo = self.mem_read(base) as u16;
+                let hi = self.mem_read((base & 0xFF00) | (base.wrapping_add(1) & 0x00FF)) as u16;
+                (hi << 8) | lo
             }
 
             // LDA ($44,X) => a1 44
             AddressingMode::Indirect_X => {
                 let base = self.mem_read(self.program_counter);
+                self.mem_read(base as u16);
                 let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-                let addr = self.mem_read_u16(ptr as u16);
-                addr
+                self.read_zero_page_u16(ptr)
             }
 
             // LDA ($44),Y => b1 44
             AddressingMode::Indirect_Y => {
                 let base = self.mem_read(self.program_counter);
-                let deref_base = self.mem_read_u16(base as u16);
+                let deref_base = self.read_zero_page_u16(base);
                 let deref = deref_base.wrapping_add(self.register_y as u16);
                 // (+1 if page crossed)
-                if deref_base & 0xFF00 != deref & 0xFF00 {
-                    self.add_cycles += 1;
-                }
+                self.dummy_read_indexed(deref_base, deref);
                 deref
             }
 
@@ -205,16 +244,25 @@ impl<'a> CPU<'a> {
         }
     }
 
-    pub fn mem_read_u16(&mut self, pos: u16) -> u16 {
-        // FIXME
-        if pos == 0x00FF || pos == 0x02FF {
-            debug!("mem_read_u16 page boundary. {:04X}", pos);
-            let lo = self.mem_read(pos) as u16;
-            let hi = self.mem_read(pos & 0xFF00) as u16;
-            return (hi << 8) | (lo as u16);
+    // インデックスを足すとき、上位バイトの繰り上げ前のアドレスを1回空読みする。
+    // 読み込み命令はページをまたいだときだけ、書き込み命令はいつも読む。
+    fn dummy_read_indexed(&mut self, base: u16, addr: u16) {
+        let page_crossed = base & 0xFF00 != addr & 0xFF00;
+        if page_crossed || self.cycle_calc_mode != CycleCalcMode::Page {
+            self.mem_read((base & 0xFF00) | (addr & 0x00FF));
         }
+    }
+
+    // ゼロページのポインタは、$FF の次は $00 に戻る
+    fn read_zero_page_u16(&mut self, ptr: u8) -> u16 {
+        let lo = self.mem_read(ptr as u16) as u16;
+        let hi = self.mem_read(ptr.wrapping_add(1) as u16) as u16;
+        (hi << 8) | lo
+    }
+
+    pub fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
         (hi << 8) | (lo as u16)
     }
 
@@ -222,23 +270,50 @@ impl<'a> CPU<'a> {
         let hi = (data >> 8) as u8;
         let lo = (data & 0x00FF) as u8;
         self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+        self.mem_write(pos.wrapping_add(1), hi);
     }
 
     fn load_and_run(&mut self, program: Vec<u8>) {
         self.load();
-        self.reset();
+        self.power_on();
         self.run();
     }
 
-    pub fn reset(&mut self) {
+    // 電源投入
+    // see: https://www.nesdev.org/wiki/CPU_power_up_state
+    pub fn power_on(&mut self) {
         self.register_a = 0;
         self.register_x = 0;
         self.register_y = 0;
-        // FIXME あってる？
         self.status = FLAG_INTERRRUPT | FLAG_BREAK2;
-        self.stack_pointer = 0xFD;
+        // リセットのシーケンスで3つ減って$FDになる
+        self.stack_pointer = 0;
+        self.bus.power_on();
+        self.reset_sequence();
+    }
 
+    // リセットボタン。A, X, Y はそのまま
+    pub fn reset(&mut self) {
+        self.bus.reset();
+        self.reset_sequence();
+    }
+
+    // 割り込みと同じ7サイクルだが、書き込みが読み込みになるので、
+    // スタックには何も積まれずSPだけ3つ減る
+    fn reset_sequence(&mut self) {
+        self.halted = false;
+        self.need_nmi = false;
+        self.prev_need_nmi = false;
+        self.run_irq = false;
+        self.prev_run_irq = false;
+
+        self.mem_read(self.program_counter);
+        self.mem_read(self.program_counter);
+        for _ in 0..3 {
+            self._dummy_stack_read();
+            self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+        }
+        self.status = self.status | FLAG_INTERRRUPT;
         self.program_counter = self.mem_read_u16(0xFFFC);
     }
 
@@ -255,91 +330,215 @@ impl<'a> CPU<'a> {
         F: FnMut(&mut CPU),
     {
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt_nmi();
+            self.step(&mut callback);
+        }
+    }
+
+    // 1命令進める (止まっているときは1サイクル)
+    pub fn step<F>(&mut self, callback: &mut F)
+    where
+        F: FnMut(&mut CPU),
+    {
+        match self.bus.take_reset_request() {
+            Some(ResetKind::Soft) => self.reset(),
+            Some(ResetKind::PowerCycle) => self.power_on(),
+            None => {}
+        }
+
+        if self.halted {
+            // CPUが止まっても、PPUとAPUは動き続ける
+            self.bus.tick(1);
+            return;
+        }
+
+        let opscode = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+
+        let op = CPU_OPS_TABLE[opscode as usize];
+        match op {
+            Some(op) => {
+                self.cycle_calc_mode = op.cycle_calc_mode.clone();
+
+                callback(self);
+
+                // オペランドのない命令も、次のバイトを読んで捨てる
+                if op.addressing_mode == AddressingMode::Implied
+                    || op.addressing_mode == AddressingMode::Accumulator
+                {
+                    self.mem_read(self.program_counter);
+                }
+
+                // サイクルはメモリアクセスごとに進むので、ここでは数えない
+                call(self, &op);
+
+                // if program_conter_state == self.program_counter {
+                //   self.program_counter += (op.len - 1) as u16
+                // }
             }
+            None => panic!("no implementation {:<02X}", opscode),
+        }
+
+        if self.prev_need_nmi || self.prev_run_irq {
+            self.interrupt();
+        }
+    }
+
+    // OAM DMA ($4014) と DMC DMA。CPUを止めて、その間のサイクルでバスを使う。
+    // 止まっている間、CPUは読もうとしていたアドレスを読み続ける。
+    // see: https://www.nesdev.org/wiki/DMA
+    fn run_dma(&mut self, addr: u16) {
+        // $4016/$4017 を続けて読んでも、コントローラーには1回の読み込みにしか見えない。
+        // 止めるサイクルの読み込みと、CPUの本来の読み込みの2回でシフトされ、1bit飛ぶ (DMCとコントローラーの競合)。
+        // PAL (2A07) ではこの空読みはバスに出ない。
+        let skip_dummy_reads = addr == 0x4016 || addr == 0x4017 || unsafe { REGION } == Region::PAL;
 
-            if self.bus.poll_apu_irq() {
-                self.apu_irq();
-            } else if unsafe { MAPPER.is_irq() } {
-                self.apu_irq();
+        // CPUを止めるサイクル
+        self.bus.tick(1);
+        if unsafe { REGION } != Region::PAL {
+            self.bus.mem_read(addr);
+        }
+        self.poll_interrupts();
+
+        let mut oam_page = self.bus.take_oam_dma();
+        let mut oam_count: u16 = 0;
+        let mut oam_value: u8 = 0;
+        // DMCは、止めるサイクルと空読みの1サイクルの後に読める
+        let mut dmc_running = self.bus.dmc_dma_pending();
+        let mut dmc_need_halt = false;
+        let mut dmc_need_dummy = true;
+
+        while dmc_running || oam_page.is_some() {
+            let get_cycle = self.bus.is_get_cycle();
+            let dmc_ready = dmc_running && !dmc_need_halt && !dmc_need_dummy;
+            // OAM DMAのサイクルは、DMC DMAの止めるサイクルと空読みも兼ねる
+            if dmc_need_halt {
+                dmc_need_halt = false;
+            } else if dmc_need_dummy {
+                dmc_need_dummy = false;
             }
 
-            let opscode = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-
-            let op = CPU_OPS_CODES.get(&opscode);
-            match op {
-                Some(op) => {
-                    self.add_cycles = 0;
-
-                    callback(self);
-                    call(self, &op);
-
-                    match op.cycle_calc_mode {
-                        CycleCalcMode::None => {
-                            self.add_cycles = 0;
-                        }
-                        CycleCalcMode::Page => {
-                            if self.add_cycles > 1 {
-                                panic!(
-                                    "Unexpected cycle_calc. {} {:?} => {}",
-                                    op.name, op.addressing_mode, self.add_cycles
-                                )
-                            }
-                        }
-                        _ => {}
-                    }
-
-                    self.bus.tick(op.cycles + self.add_cycles);
-
-                    // if program_conter_state == self.program_counter {
-                    //   self.program_counter += (op.len - 1) as u16
-                    // }
+            self.bus.tick(1);
+            if get_cycle {
+                if dmc_ready {
+                    self.bus.dmc_dma_read();
+                    dmc_running = false;
+                } else if let Some(page) = oam_page {
+                    oam_value = self.bus.mem_read((page as u16) << 8 | oam_count / 2);
+                    oam_count += 1;
+                } else if !skip_dummy_reads {
+                    self.bus.mem_read(addr);
+                }
+            } else if oam_page.is_some() && oam_count & 0x01 == 1 {
+                self.bus.mem_write(0x2004, oam_value);
+                oam_count += 1;
+                if oam_count == 0x200 {
+                    oam_page = None;
                 }
-                _ => {} // panic!("no implementation {:<02X}", opscode),
+            } else if !skip_dummy_reads {
+                // 読み込み側のサイクルに合わせる
+                self.bus.mem_read(addr);
+            }
+            self.poll_interrupts();
+
+            // OAM DMAの途中でDMCのサンプルが必要になった
+            if !dmc_running && self.bus.dmc_dma_pending() {
+                dmc_running = true;
+                dmc_need_halt = true;
+                dmc_need_dummy = true;
             }
         }
     }
 
-    fn interrupt_nmi(&mut self) {
-        debug!("** INTERRUPT_NMI **");
-        self._push_u16(self.program_counter);
-        let mut status = self.status;
-        status = status & !FLAG_BREAK;
-        status = status | FLAG_BREAK2;
-        self._push(status);
+    // サイクルの終わりに、割り込みの信号を見る
+    fn poll_interrupts(&mut self) {
+        self.prev_need_nmi = self.need_nmi;
+        let nmi_line = self.bus.nmi_line();
+        if !self.nmi_line && nmi_line {
+            self.need_nmi = true;
+        }
+        self.nmi_line = nmi_line;
 
-        self.status = self.status | FLAG_INTERRRUPT;
-        self.bus.tick(2);
-        self.program_counter = self.mem_read_u16(0xFFFA);
+        self.prev_run_irq = self.run_irq;
+        self.run_irq = self.bus.irq_line() && self.status & FLAG_INTERRRUPT == 0;
     }
 
-    fn apu_irq(&mut self) {
-        if self.status & FLAG_INTERRRUPT != 0 {
-            return;
-        }
-        self._push_u16(self.program_counter);
-        self._push(self.status);
-        self.program_counter = self.mem_read_u16(0xFFFE);
-        self.status = self.status | FLAG_BREAK;
+    // NMI/IRQの割り込みシーケンス (7サイクル)
+    fn interrupt(&mut self) {
+        // 命令を読むが、捨てる (PCも進めない)
+        self.mem_read(self.program_counter);
+        self.mem_read(self.program_counter);
+        self._interrupt(self.program_counter, FLAG_BREAK2);
+    }
+
+    // PCとステータスをプッシュして、割り込みベクタに飛ぶ (BRKと共通)
+    fn _interrupt(&mut self, return_addr: u16, break_flags: u8) {
+        self._push_u16(return_addr);
+
+        // ステータスをプッシュする前にNMIが来ていれば、IRQ/BRKはNMIに乗っ取られる
+        let vector = if self.need_nmi {
+            debug!("** INTERRUPT_NMI **");
+            self.need_nmi = false;
+            0xFFFA
+        } else {
+            0xFFFE
+        };
+        self._push((self.status & !FLAG_BREAK) | break_flags);
+        self.status = self.status | FLAG_INTERRRUPT;
+        self.program_counter = self.mem_read_u16(vector);
     }
 
     pub fn anc(&mut self, mode: &AddressingMode) {
-        todo!("anc")
+        // AND #{imm} のあと、Nフラグをキャリーにコピーする
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        self.register_a = self.register_a & value;
+        self.update_zero_and_negative_flags(self.register_a);
+        self.set_carry(self.register_a & SIGN_BIT != 0);
     }
+
     pub fn arr(&mut self, mode: &AddressingMode) {
-        todo!("arr")
+        // AND #{imm} + ROR A
+        // C は結果のbit6、V は結果のbit6 xor bit5 になる
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        let carry = self.status & FLAG_CARRY;
+        self.register_a = ((self.register_a & value) >> 1) | (carry << 7);
+        self.update_zero_and_negative_flags(self.register_a);
+
+        let bit6 = (self.register_a >> 6) & 0x01;
+        let bit5 = (self.register_a >> 5) & 0x01;
+        self.set_carry(bit6 == 1);
+        self.status = if bit6 ^ bit5 == 1 {
+            self.status | FLAG_OVERFLOW
+        } else {
+            self.status & !FLAG_OVERFLOW
+        };
     }
+
     pub fn asr(&mut self, mode: &AddressingMode) {
-        todo!("asr")
+        // AND #{imm} + LSR A
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        let value = self.register_a & value;
+        self.set_carry(value & 0x01 == 1);
+        self.register_a = value >> 1;
+        self.update_zero_and_negative_flags(self.register_a);
     }
+
     pub fn lxa(&mut self, mode: &AddressingMode) {
-        todo!("lxa")
+        // (A OR CONST) AND #{imm} into A and X
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        self.register_a = (self.register_a | UNSTABLE_MAGIC) & value;
+        self.register_x = self.register_a;
+        self.update_zero_and_negative_flags(self.register_a);
     }
+
     pub fn sha(&mut self, mode: &AddressingMode) {
-        todo!("sha")
+        // A&X&H into {adr}
+        self.store_and_high(mode, self.register_a & self.register_x);
     }
+
     pub fn sbx(&mut self, mode: &AddressingMode) {
         //  A&X minus #{imm} into X
         // AND X register with accumulator and store result in X regis-ter, then
@@ -349,21 +548,21 @@ impl<'a> CPU<'a> {
         // AND X をアキュムレータに登録し、結果を X レジスタに格納します。 X レジスタからバイトを減算します (ボローなし)。 ステータスフラグ：N、Z、C
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
-        let (v, overflow) = (self.register_a & self.register_x).overflowing_sub(value);
+        let (v, borrow) = (self.register_a & self.register_x).overflowing_sub(value);
         self.register_x = v;
         self.update_zero_and_negative_flags(self.register_x);
-        self.status = if overflow {
-            self.status & FLAG_OVERFLOW
-        } else {
-            self.status | FLAG_OVERFLOW
-        };
-        todo!("sbx")
+        // CMPと同じく、引けたらキャリーが立つ
+        self.set_carry(!borrow);
     }
 
-    pub fn jam(&mut self, mode: &AddressingMode) {
+    pub fn jam(&mut self, _mode: &AddressingMode) {
         // Stop program counter (processor lock up).
+        // リセットされるまで、同じ命令に止まり続ける
         self.program_counter -= 1;
-        panic!("CALL JAM operation.");
+        if !self.halted {
+            error!("CPU JAMMED at {:04X}", self.program_counter);
+        }
+        self.halted = true;
     }
 
     pub fn lae(&mut self, mode: &AddressingMode) {
@@ -374,78 +573,113 @@ impl<'a> CPU<'a> {
         // Status flags: N,Z
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
-        let s = self._pop();
-        self.register_a = value & s;
+        self.register_a = value & self.stack_pointer;
         self.register_x = self.register_a;
-        self._push(self.register_a);
+        self.stack_pointer = self.register_a;
         self.update_zero_and_negative_flags(self.register_a);
-        todo!("lae")
     }
 
     pub fn shx(&mut self, mode: &AddressingMode) {
         // M =3D X AND HIGH(arg) + 1
-        let addr = self.get_operand_address(mode);
-        let h = ((addr & 0xFF00) >> 8) as u8;
-        self.mem_write(addr, (self.register_x & h).wrapping_add(1));
-        todo!("shx")
+        self.store_and_high(mode, self.register_x);
     }
 
     pub fn shy(&mut self, mode: &AddressingMode) {
         // Y&H into {adr}
         // AND Y register with the high byte of the target address of the argument
         // + 1. Store the result in memory.
-        let addr = self.get_operand_address(mode);
-        let h = ((addr & 0xFF00) >> 8) as u8;
-        self.mem_write(addr, (self.register_y & h).wrapping_add(1));
-        todo!("shy")
+        self.store_and_high(mode, self.register_y);
     }
 
     pub fn ane(&mut self, mode: &AddressingMode) {
-        // TXA + AND #{imm}
-        self.txa(mode);
-        self.and(mode);
-        todo!("ane")
+        // (A OR CONST) AND X AND #{imm} into A
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        self.register_a = (self.register_a | UNSTABLE_MAGIC) & self.register_x & value;
+        self.update_zero_and_negative_flags(self.register_a);
     }
 
     pub fn shs(&mut self, mode: &AddressingMode) {
         // stores A&X into S and A&X&H into {adr}
         // アキュムレータと X レジスタを AND 演算し、結果をスタック ポインタに格納します。次に、スタック ポインタと引数 1 のターゲット アドレスの上位バイトを AND 演算します。結果をメモリに格納します。
-        self._push(self.register_a & self.register_x);
-        let addr = self.get_operand_address(mode);
-        let h = ((addr & 0xFF00) >> 8) as u8;
-        self.mem_write(addr, self.register_a & self.register_x & h);
-        todo!("shs")
+        self.stack_pointer = self.register_a & self.register_x;
+        self.store_and_high(mode, self.stack_pointer);
+    }
+
+    // SHA/SHX/SHY/SHS 共通。
+    // value & (ベースアドレスの上位バイト + 1) を書き込む。
+    // ページをまたぐと、書き込み先の上位バイトも書き込む値に化ける。
+    fn store_and_high(&mut self, mode: &AddressingMode, value: u8) {
+        let (base, index) = match mode {
+            AddressingMode::Absolute_X => {
+                (self.mem_read_u16(self.program_counter), self.register_x)
+            }
+            AddressingMode::Absolute_Y => {
+                (self.mem_read_u16(self.program_counter), self.register_y)
+            }
+            AddressingMode::Indirect_Y => {
+                let ptr = self.mem_read(self.program_counter);
+                let lo = self.mem_read(ptr as u16) as u16;
+                let hi = self.mem_read(ptr.wrapping_add(1) as u16) as u16;
+                ((hi << 8) | lo, self.register_y)
+            }
+            _ => panic!("mode {:?} is not supported", mode),
+        };
+        let addr = base.wrapping_add(index as u16);
+        self.mem_read((base & 0xFF00) | (addr & 0x00FF));
+        let h = (base >> 8) as u8;
+        let value = value & h.wrapping_add(1);
+        let addr = if base & 0xFF00 != addr & 0xFF00 {
+            ((value as u16) << 8) | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.mem_write(addr, value);
+    }
+
+    fn set_carry(&mut self, carry: bool) {
+        self.status = if carry {
+            self.status | FLAG_CARRY
+        } else {
+            self.status & !FLAG_CARRY
+        };
     }
 
     pub fn rra(&mut self, mode: &AddressingMode) {
-        self.ror(mode);
-        self.adc(mode);
+        let addr = self.get_operand_address(mode);
+        let value = self._modify(addr, Self::_ror);
+        self._adc(value);
     }
 
     pub fn sre(&mut self, mode: &AddressingMode) {
-        self.lsr(mode);
-        self.eor(mode);
+        let addr = self.get_operand_address(mode);
+        let value = self._modify(addr, Self::_lsr);
+        self._eor(value);
     }
 
     pub fn rla(&mut self, mode: &AddressingMode) {
-        self.rol(mode);
-        self.and(mode);
+        let addr = self.get_operand_address(mode);
+        let value = self._modify(addr, Self::_rol);
+        self._and(value);
     }
 
     pub fn slo(&mut self, mode: &AddressingMode) {
-        self.asl(mode);
-        self.ora(mode);
+        let addr = self.get_operand_address(mode);
+        let value = self._modify(addr, Self::_asl);
+        self._ora(value);
     }
 
     pub fn isb(&mut self, mode: &AddressingMode) {
         // = ISC
-        self.inc(mode);
-        self.sbc(mode);
+        let addr = self.get_operand_address(mode);
+        let value = self._modify(addr, Self::_inc);
+        self._sbc(value);
     }
 
     pub fn dcp(&mut self, mode: &AddressingMode) {
-        self.dec(mode);
-        self.cmp(mode);
+        let addr = self.get_operand_address(mode);
+        let value = self._modify(addr, Self::_dec);
+        self._compare(self.register_a, value);
     }
 
     pub fn sax(&mut self, mode: &AddressingMode) {
@@ -504,11 +738,13 @@ impl<'a> CPU<'a> {
 
     pub fn rti(&mut self, mode: &AddressingMode) {
         // スタックからプロセッサ フラグをプルし、続いてプログラム カウンタをプルします。
+        self._dummy_stack_read();
         self.status = self._pop() & !FLAG_BREAK | FLAG_BREAK2;
         self.program_counter = self._pop_u16();
     }
 
     pub fn plp(&mut self, mode: &AddressingMode) {
+        self._dummy_stack_read();
         self.status = self._pop() & !FLAG_BREAK | FLAG_BREAK2;
     }
 
@@ -517,6 +753,7 @@ impl<'a> CPU<'a> {
     }
 
     pub fn pla(&mut self, mode: &AddressingMode) {
+        self._dummy_stack_read();
         self.register_a = self._pop();
         self.update_zero_and_negative_flags(self.register_a);
     }
@@ -526,7 +763,11 @@ impl<'a> CPU<'a> {
     }
 
     pub fn nop(&mut self, mode: &AddressingMode) {
-        // なにもしない
+        // なにもしないが、非公式のNOPはオペランドを読む
+        if mode != &AddressingMode::Implied {
+            let addr = self.get_operand_address(mode);
+            self.mem_read(addr);
+        }
     }
 
     pub fn ldy(&mut self, mode: &AddressingMode) {
@@ -551,18 +792,30 @@ impl<'a> CPU<'a> {
     }
 
     pub fn rts(&mut self, mode: &AddressingMode) {
-        let value = self._pop_u16() + 1;
-        self.program_counter = value;
+        self._dummy_stack_read();
+        let value = self._pop_u16();
+        // 戻り先-1を読み捨ててから、PCを進める
+        self.mem_read(value);
+        self.program_counter = value.wrapping_add(1);
     }
 
     pub fn jsr(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+        // 下位バイトを読んだあと、戻り先をプッシュしてから上位バイトを読む
+        let lo = self.mem_read(self.program_counter) as u16;
+        self._dummy_stack_read();
         self._push_u16(self.program_counter + 2 - 1);
+        let hi = self.mem_read(self.program_counter + 1) as u16;
+        let addr = (hi << 8) | lo;
         self.program_counter = addr;
         // 後で+2するので整合性のため-2しておく
         self.program_counter -= 2;
     }
 
+    // スタックからプルする命令は、SPを進める前に1回空読みする
+    fn _dummy_stack_read(&mut self) {
+        self.mem_read(0x0100 + self.stack_pointer as u16);
+    }
+
     pub fn _push(&mut self, value: u8) {
         let addr = 0x0100 + self.stack_pointer as u16;
         trace!("STACK PUSH: {:04X} => {:02X}", self.stack_pointer, value);
@@ -593,8 +846,8 @@ impl<'a> CPU<'a> {
         self.program_counter = addr;
         // 後で+2するので整合性のため-2しておく
         self.program_counter -= 2;
-        // TODO
-        // オリジナルの 6502 は、間接ベクトルがページ境界にある場合、ターゲット アドレスを正しくフェッチしません (たとえば、$xxFF で、xx は $00 から $FF までの任意の値です)。この場合、予想どおり $xxFF から LSB を取得しますが、$xx00 から MSB を取得します。これは、65SC02 などの最近のチップで修正されているため、互換性のために、間接ベクトルがページの最後にないことを常に確認してください。
+        // オリジナルの 6502 は、間接ベクトルがページ境界にある場合、ターゲット アドレスを正しくフェッチしません (たとえば、$xxFF で、xx は $00 から $FF までの任意の値です)。この場合、予想どおり $xxFF から LSB を取得しますが、$xx00 から MSB を取得します。
+        // => get_operand_address で対応している
     }
 
     pub fn iny(&mut self, mode: &AddressingMode) {
@@ -609,9 +862,13 @@ impl<'a> CPU<'a> {
 
     pub fn inc(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr).wrapping_add(1);
-        self.mem_write(addr, value);
+        self._modify(addr, Self::_inc);
+    }
+
+    fn _inc(&mut self, value: u8) -> u8 {
+        let value = value.wrapping_add(1);
         self.update_zero_and_negative_flags(value);
+        value
     }
 
     pub fn dey(&mut self, mode: &AddressingMode) {
@@ -626,14 +883,31 @@ impl<'a> CPU<'a> {
 
     pub fn dec(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr).wrapping_sub(1);
-        self.mem_write(addr, value);
+        self._modify(addr, Self::_dec);
+    }
+
+    fn _dec(&mut self, value: u8) -> u8 {
+        let value = value.wrapping_sub(1);
         self.update_zero_and_negative_flags(value);
+        value
+    }
+
+    // 読んで、元の値をそのまま書き戻してから、新しい値を書く (Read-Modify-Write)
+    fn _modify(&mut self, addr: u16, f: fn(&mut Self, u8) -> u8) -> u8 {
+        let value = self.mem_read(addr);
+        self.mem_write(addr, value);
+        let value = f(self, value);
+        self.mem_write(addr, value);
+        value
     }
 
     fn _cmp(&mut self, target: u8, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
+        self._compare(target, value);
+    }
+
+    fn _compare(&mut self, target: u8, value: u8) {
         if target >= value {
             self.sec(&AddressingMode::Implied);
         } else {
@@ -693,44 +967,35 @@ impl<'a> CPU<'a> {
 
     fn _branch(&mut self, mode: &AddressingMode, flag: u8, nonzero: bool) {
         let addr = self.get_operand_address(mode);
-        if nonzero {
-            if self.status & flag != 0 {
-                // (+1 if branch succeeds
-                //  +2 if to a new page)
-                //    => new pageの場合は、+1っぽい。
-                //     https://pgate1.at-ninja.jp/NES_on_FPGA/nes_cpu.htm#clock
-                self.add_cycles += 1;
-                if (self.program_counter & 0xFF00) != (addr & 0xFF00) {
-                    self.add_cycles += 1;
-                }
-                self.program_counter = addr
+        if (self.status & flag != 0) == nonzero {
+            // 分岐する場合、最後のサイクルではIRQを見ないので、次の命令の後に割り込む
+            if self.run_irq && !self.prev_run_irq {
+                self.run_irq = false;
             }
-        } else {
-            if self.status & flag == 0 {
-                // (+1 if branch succeeds
-                //  +2 if to a new page)
-                self.add_cycles += 1;
-                if (self.program_counter & 0xFF00) != (addr & 0xFF00) {
-                    self.add_cycles += 1;
-                }
-                self.program_counter = addr
+            // (+1 if branch succeeds
+            //  +2 if to a new page)
+            //    => new pageの場合は、+1っぽい。
+            //     https://pgate1.at-ninja.jp/NES_on_FPGA/nes_cpu.htm#clock
+            // 後で+1するので、次の命令と飛び先はどちらも+1したアドレス
+            let next = self.program_counter.wrapping_add(1);
+            let target = addr.wrapping_add(1);
+            self.mem_read(next);
+            if (next & 0xFF00) != (target & 0xFF00) {
+                // 上位バイトを直す前のアドレスを空読みする
+                self.mem_read((next & 0xFF00) | (target & 0x00FF));
             }
+            self.program_counter = addr
         }
     }
 
     pub fn brk(&mut self, mode: &AddressingMode) {
-        // FLAG_BREAK が立っている場合は
-        if self.status & FLAG_BREAK != 0 {
-            return;
-        }
-
         // プログラム カウンターとプロセッサ ステータスがスタックにプッシュされ、
-        self._push_u16(self.program_counter + 1);
-        self._push(self.status);
+        // $FFFE/F の IRQ 割り込みベクトルが PC にロードされます。
+        // ブレーク フラグはプッシュした値にだけ立ちます。
+        self._interrupt(self.program_counter + 1, FLAG_BREAK | FLAG_BREAK2);
 
-        // $FFFE/F の IRQ 割り込みベクトルが PC にロードされ、ステータスのブレーク フラグが 1 に設定されます。
-        self.program_counter = self.mem_read_u16(0xFFFE);
-        self.status = self.status | FLAG_BREAK;
+        // 割り込みハンドラの最初の命令は、NMIより先に実行される
+        self.prev_need_nmi = false;
     }
 
     pub fn bpl(&mut self, mode: &AddressingMode) {
@@ -772,97 +1037,80 @@ impl<'a> CPU<'a> {
     }
 
     pub fn ror(&mut self, mode: &AddressingMode) {
-        let (value, carry) = if mode == &AddressingMode::Accumulator {
-            let carry = self.register_a & 0x01;
-            self.register_a = self.register_a / 2;
-            self.register_a = self.register_a | ((self.status & FLAG_CARRY) << 7);
-            (self.register_a, carry)
+        if mode == &AddressingMode::Accumulator {
+            self.register_a = self._ror(self.register_a);
         } else {
             let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let carry = value & 0x01;
-            let value = value / 2;
-            let value = value | ((self.status & FLAG_CARRY) << 7);
-            self.mem_write(addr, value);
-            (value, carry)
-        };
+            self._modify(addr, Self::_ror);
+        }
+    }
 
-        self.status = if carry == 1 {
-            self.status | FLAG_CARRY
-        } else {
-            self.status & !FLAG_CARRY
-        };
+    fn _ror(&mut self, value: u8) -> u8 {
+        let carry = value & 0x01;
+        let value = value / 2;
+        let value = value | ((self.status & FLAG_CARRY) << 7);
+        self.set_carry(carry == 1);
         self.update_zero_and_negative_flags(value);
+        value
     }
 
     pub fn rol(&mut self, mode: &AddressingMode) {
-        let (value, carry) = if mode == &AddressingMode::Accumulator {
-            let (value, carry) = self.register_a.overflowing_mul(2);
-            self.register_a = value | (self.status & FLAG_CARRY);
-            (self.register_a, carry)
+        if mode == &AddressingMode::Accumulator {
+            self.register_a = self._rol(self.register_a);
         } else {
             let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let (value, carry) = value.overflowing_mul(2);
-            let value = value | (self.status & FLAG_CARRY);
-            self.mem_write(addr, value);
-            (value, carry)
-        };
+            self._modify(addr, Self::_rol);
+        }
+    }
 
-        self.status = if carry {
-            self.status | FLAG_CARRY
-        } else {
-            self.status & !FLAG_CARRY
-        };
+    fn _rol(&mut self, value: u8) -> u8 {
+        let (value, carry) = value.overflowing_mul(2);
+        let value = value | (self.status & FLAG_CARRY);
+        self.set_carry(carry);
         self.update_zero_and_negative_flags(value);
+        value
     }
 
     pub fn lsr(&mut self, mode: &AddressingMode) {
-        let (value, carry) = if mode == &AddressingMode::Accumulator {
-            let carry = self.register_a & 0x01;
-            self.register_a = self.register_a / 2;
-            (self.register_a, carry)
+        if mode == &AddressingMode::Accumulator {
+            self.register_a = self._lsr(self.register_a);
         } else {
             let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let carry = value & 0x01;
-            let value = value / 2;
-            self.mem_write(addr, value);
-            (value, carry)
-        };
+            self._modify(addr, Self::_lsr);
+        }
+    }
 
-        self.status = if carry == 1 {
-            self.status | FLAG_CARRY
-        } else {
-            self.status & !FLAG_CARRY
-        };
+    fn _lsr(&mut self, value: u8) -> u8 {
+        let carry = value & 0x01;
+        let value = value / 2;
+        self.set_carry(carry == 1);
         self.update_zero_and_negative_flags(value);
+        value
     }
 
     pub fn asl(&mut self, mode: &AddressingMode) {
-        let (value, carry) = if mode == &AddressingMode::Accumulator {
-            let (value, carry) = self.register_a.overflowing_mul(2);
-            self.register_a = value;
-            (value, carry)
+        if mode == &AddressingMode::Accumulator {
+            self.register_a = self._asl(self.register_a);
         } else {
             let addr = self.get_operand_address(mode);
-            let value = self.mem_read(addr);
-            let (value, carry) = value.overflowing_mul(2);
-            self.mem_write(addr, value);
-            (value, carry)
-        };
+            self._modify(addr, Self::_asl);
+        }
+    }
 
-        self.status = if carry {
-            self.status | FLAG_CARRY
-        } else {
-            self.status & !FLAG_CARRY
-        };
+    fn _asl(&mut self, value: u8) -> u8 {
+        let (value, carry) = value.overflowing_mul(2);
+        self.set_carry(carry);
         self.update_zero_and_negative_flags(value);
+        value
     }
 
     pub fn ora(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
+        self._ora(value);
+    }
+
+    fn _ora(&mut self, value: u8) {
         self.register_a = self.register_a | value;
         self.update_zero_and_negative_flags(self.register_a);
     }
@@ -870,6 +1118,10 @@ impl<'a> CPU<'a> {
     pub fn eor(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
+        self._eor(value);
+    }
+
+    fn _eor(&mut self, value: u8) {
         self.register_a = self.register_a ^ value;
         self.update_zero_and_negative_flags(self.register_a);
     }
@@ -877,18 +1129,25 @@ impl<'a> CPU<'a> {
     pub fn and(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
+        self._and(value);
+    }
+
+    fn _and(&mut self, value: u8) {
         self.register_a = self.register_a & value;
         self.update_zero_and_negative_flags(self.register_a);
     }
 
     pub fn sbc(&mut self, mode: &AddressingMode) {
+        let addr = self.get_operand_address(mode);
+        let value = self.mem_read(addr);
+        self._sbc(value);
+    }
+
+    fn _sbc(&mut self, value: u8) {
         // A-M-(1-C)
         // キャリーかどうかの判定が逆
         // キャリーの引き算(1-C)
         // overflowの判定が逆 = m,p, p,m
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
-
         let carry = self.status & FLAG_CARRY;
         let (v1, carry_flag1) = self.register_a.overflowing_sub(value);
         let (n, carry_flag2) = v1.overflowing_sub(1 - carry);
@@ -915,7 +1174,10 @@ impl<'a> CPU<'a> {
     pub fn adc(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let value = self.mem_read(addr);
+        self._adc(value);
+    }
 
+    fn _adc(&mut self, value: u8) {
         let carry = self.status & FLAG_CARRY;
         let (rhs, carry_flag1) = value.overflowing_add(carry);
         let (n, carry_flag2) = self.register_a.overflowing_add(rhs);
@@ -1087,7 +1349,10 @@ fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
             let hi = args[1] as u16;
             let lo = args[0] as u16;
             let addr = hi << 8 | lo;
-            let value = cpu.mem_read_u16(addr);
+            // $xxFF の場合、上位バイトは $xx00 から読む (6502のバグ)
+            let value_lo = cpu.mem_read(addr) as u16;
+            let value_hi = cpu.mem_read((addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF)) as u16;
+            let value = value_hi << 8 | value_lo;
             return format!("= {:<04X}", value);
         }
         return format!("");
@@ -1134,13 +1399,13 @@ fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
         AddressingMode::Indirect_X => {
             let base = args[0];
             let ptr: u8 = (base as u8).wrapping_add(cpu.register_x);
-            let addr = cpu.mem_read_u16(ptr as u16);
+            let addr = cpu.read_zero_page_u16(ptr);
             let value = cpu.mem_read(addr);
             format!("@ {:<02X} = {:<04X} = {:<02X}", ptr, addr, value)
         }
         AddressingMode::Indirect_Y => {
             let base = args[0];
-            let deref_base = cpu.mem_read_u16(base as u16);
+            let deref_base = cpu.read_zero_page_u16(base);
             let deref = deref_base.wrapping_add(cpu.register_y as u16);
             let value = cpu.mem_read(deref);
             format!("= {:<04X} @ {:<04X} = {:<02X}", deref_base, deref, value)
@@ -1162,13 +1427,27 @@ fn cpu2str(cpu: &CPU) -> String {
 mod test {
 
     use super::*;
+    use crate::apu::NesAPU;
     use crate::bus::Bus;
-    use crate::cartridge::test::test_rom;
-    use crate::ppu::NesPPU;
+    use crate::mapper::test::insert_mapper;
+    use crate::rom::Rom;
+
+    fn test_bus<'a>() -> Bus<'a> {
+        Bus::new(NesAPU::headless(), |_, _, _| None)
+    }
+
+    fn run_trace(cpu: &mut CPU, steps: usize) -> Vec<String> {
+        let mut result: Vec<String> = vec![];
+        for _ in 0..steps {
+            cpu.step(&mut |cpu| result.push(trace(cpu)));
+        }
+        result
+    }
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new(test_rom(), move |ppu: &NesPPU| {});
+        let _mapper = insert_mapper(Rom::empty());
+        let mut bus = test_bus();
         bus.mem_write(100, 0xa2);
         bus.mem_write(101, 0x01);
         bus.mem_write(102, 0xca);
@@ -1181,10 +1460,7 @@ mod test {
         cpu.register_x = 2;
         cpu.register_y = 3;
 
-        let mut result: Vec<String> = vec![];
-        cpu.run_with_callback(|cpu| {
-            result.push(trace(cpu));
-        });
+        let result = run_trace(&mut cpu, 3);
 
         assert_eq!(
             "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
@@ -1202,7 +1478,8 @@ mod test {
 
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new(test_rom(), move |ppu: &NesPPU| {});
+        let _mapper = insert_mapper(Rom::empty());
+        let mut bus = test_bus();
         // ORA ($33), Y
         bus.mem_write(100, 0x11);
         bus.mem_write(101, 0x33);
@@ -1218,16 +1495,118 @@ mod test {
         cpu.program_counter = 0x64;
         cpu.register_y = 0;
 
-        let mut result: Vec<String> = vec![];
-        cpu.run_with_callback(|cpu| {
-            result.push(trace(cpu));
-        });
+        let result = run_trace(&mut cpu, 1);
         assert_eq!(
             "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
             result[0]
         );
     }
 
+    #[test]
+    fn test_mem_read_u16_wraps_around() {
+        let mut rom = Rom::empty();
+        rom.prg_rom = vec![0; 0x8000];
+        rom.prg_rom[0x7FFF] = 0x34;
+        let _mapper = insert_mapper(rom);
+        let mut bus = test_bus();
+        bus.mem_write(0x0000, 0x12);
+
+        // $FFFF の次は $0000
+        let mut cpu = CPU::new(bus);
+        assert_eq!(cpu.mem_read_u16(0xFFFF), 0x1234);
+    }
+
+    #[test]
+    fn test_opcode_table_covers_all_opcodes() {
+        for code in 0..=0xFF {
+            let op =
+                CPU_OPS_TABLE[code as usize].unwrap_or_else(|| panic!("no opcode {:02X}", code));
+            assert_eq!(op.code, code);
+            let bytes = match op.addressing_mode {
+                AddressingMode::Implied | AddressingMode::Accumulator => 1,
+                AddressingMode::Absolute
+                | AddressingMode::Absolute_X
+                | AddressingMode::Absolute_Y
+                | AddressingMode::Indirect => 3,
+                _ => 2,
+            };
+            assert_eq!(op.bytes, bytes, "{:02X} {}", code, op.name);
+        }
+    }
+
+    #[test]
+    fn test_opcode_table_dispatch() {
+        let _mapper = insert_mapper(Rom::empty());
+        let mut bus = test_bus();
+        let program = [
+            0xA9, 0x10, // LDA #$10
+            0xA7, 0x00, // *LAX $00
+            0xE8, // INX
+            0x0C, 0x34, 0x12, // *NOP $1234
+            0x69, 0x01, // ADC #$01
+        ];
+        for (i, data) in program.iter().enumerate() {
+            bus.mem_write(0x0200 + i as u16, *data);
+        }
+        bus.mem_write(0x0000, 0x22);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0200;
+        run_trace(&mut cpu, 5);
+        assert_eq!(cpu.register_a, 0x23);
+        assert_eq!(cpu.register_x, 0x23);
+        assert_eq!(cpu.program_counter, 0x0200 + program.len() as u16);
+    }
+
+    // VBlankフラグが立つ前後で$2002を読む。
+    // dot: 読み込みのとき、241ラインで最後に処理し終わったドット
+    // => ($2002のbit7, NMIが発生したか)
+    fn read_status_around_vblank(dot: isize) -> (bool, bool) {
+        // NMIベクタは$8000。ROMはNOPで埋める
+        let mut rom = Rom::empty();
+        rom.prg_rom = vec![0xEA; 0x8000];
+        rom.prg_rom[0x7FFA] = 0x00;
+        rom.prg_rom[0x7FFB] = 0x80;
+        let _mapper = insert_mapper(rom);
+        let mut bus = test_bus();
+        let program = [
+            0xAD, 0x02, 0x20, // LDA $2002
+            0xEA, 0xEA, 0xEA, // NOP
+        ];
+        for (i, data) in program.iter().enumerate() {
+            bus.mem_write(0x0200 + i as u16, *data);
+        }
+        bus.ppu().ctrl.update(0x80);
+        // LDA abs の読み込みは4サイクル目 (3ドット x 4 進めてから読む)
+        bus.ppu().set_position(240, (330 + dot) as usize);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x0200;
+        cpu.step(&mut |_| {});
+        let flag = cpu.register_a & 0x80 != 0;
+        let mut nmi = cpu.program_counter >= 0x8000;
+        for _ in 0..3 {
+            cpu.step(&mut |_| {});
+            nmi |= cpu.program_counter >= 0x8000;
+        }
+        (flag, nmi)
+    }
+
+    #[test]
+    fn test_read_status_vblank_race() {
+        // see: https://www.nesdev.org/wiki/PPU_frame_timing#VBL_Flag_Timing
+        // 間に合わなければ、あとでフラグが立ってNMIも来る
+        assert_eq!(read_status_around_vblank(-1), (false, true));
+        // 1ドット前: フラグは立たず、NMIも来ない
+        assert_eq!(read_status_around_vblank(0), (false, false));
+        // 立った直後: フラグは読めるが、NMIは来ない
+        assert_eq!(read_status_around_vblank(1), (true, false));
+        assert_eq!(read_status_around_vblank(2), (true, false));
+        // それ以降は、CPUがNMIを見たあとに読んでいる
+        assert_eq!(read_status_around_vblank(3), (true, true));
+        assert_eq!(read_status_around_vblank(4), (true, true));
+    }
+
     /* Instruction tests
     use super::*;
     fn run<F>(program: Vec<u8>, f: F) -> CPU
@@ -1,7 +1,19 @@
-use crate::palette;
+use crate::palette::{self, PALETTE};
+
+// ドットがどこから描画されたか
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layer {
+    Backdrop,
+    Background,
+    Sprite(u8), // OAMの番号 (0-63)
+}
 
 pub struct Frame {
+    // RGB24
     pub data: Vec<u8>,
+    // PPUが出力したままの値 (eee pppppp: エンファシス, パレット番号)
+    pub indexes: Vec<u16>,
+    pub layers: Vec<Layer>,
 }
 
 impl Frame {
@@ -11,6 +23,8 @@ impl Frame {
     pub fn new() -> Self {
         Frame {
             data: vec![0; (Frame::WIDTH) * (Frame::HEIGHT) * 3],
+            indexes: vec![0; (Frame::WIDTH) * (Frame::HEIGHT)],
+            layers: vec![Layer::Backdrop; (Frame::WIDTH) * (Frame::HEIGHT)],
         }
     }
 
@@ -22,6 +36,25 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
+
+    // PPUの出力を置く。RGBは現在のパレットで求める。
+    pub fn set_indexed_pixel(&mut self, x: usize, y: usize, index: u16, layer: Layer) {
+        let i = y * Frame::WIDTH + x;
+        if i < self.indexes.len() {
+            self.indexes[i] = index;
+            self.layers[i] = layer;
+            let rgb = unsafe { PALETTE.color((index & 0x3F) as u8, (index >> 6) as u8) };
+            self.set_pixel(x, y, rgb);
+        }
+    }
+
+    pub fn index(&self, x: usize, y: usize) -> u16 {
+        self.indexes[y * Frame::WIDTH + x]
+    }
+
+    pub fn layer(&self, x: usize, y: usize) -> Layer {
+        self.layers[y * Frame::WIDTH + x]
+    }
 }
 
 pub fn show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
@@ -0,0 +1,124 @@
+use crate::rom::Region;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+
+// ゲームのデータベース。iNES 1.0 のヘッダーでは表せない情報 (submapper, PRG RAM, 地域) を、
+// ROMのCRC32 (ヘッダーを除いたPRG ROM + CHR ROM) から引く。
+//   - nes20db.xml (NES 2.0 XML Database) があれば、そちらを優先する
+//   - なければ、組み込みの表 (ヘッダーだけでは正しく動かないゲーム) を使う
+// see: https://www.nesdev.org/wiki/NES_2.0_XML_Database
+const GAME_DB: &str = "nes20db.xml";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GameInfo {
+    pub mapper: u16,
+    pub submapper: u8,
+    // 揮発 + 不揮発
+    pub prg_ram_size: Option<usize>,
+    pub region: Option<Region>,
+}
+
+impl GameInfo {
+    const fn new(mapper: u16, submapper: u8) -> Self {
+        GameInfo {
+            mapper: mapper,
+            submapper: submapper,
+            prg_ram_size: None,
+            region: None,
+        }
+    }
+}
+
+// 組み込みの表
+static GAMES: [(u32, GameInfo); 2] = [
+    // MMC6 (1KBの内蔵PRG RAM)
+    (0x889129CB, GameInfo::new(4, 1)), // StarTropics (USA)
+    (0xD054FFB0, GameInfo::new(4, 1)), // Zoda's Revenge - StarTropics II (USA)
+];
+
+static DATABASE: Lazy<HashMap<u32, GameInfo>> = Lazy::new(|| {
+    let mut games: HashMap<u32, GameInfo> = GAMES.iter().cloned().collect();
+    match std::fs::read_to_string(GAME_DB) {
+        Ok(xml) => {
+            let loaded = parse_xml(&xml);
+            info!("{} games loaded from {}", loaded.len(), GAME_DB);
+            games.extend(loaded);
+        }
+        Err(_) => info!("{} not found, using the built-in game list", GAME_DB),
+    }
+    games
+});
+
+pub fn lookup(crc: u32) -> Option<GameInfo> {
+    DATABASE.get(&crc).copied()
+}
+
+// <game>
+//   <rom size="..." crc32="XXXXXXXX" .../>
+//   <prgram size="..."/> <prgnvram size="..."/>
+//   <pcb mapper="4" submapper="1" .../>
+//   <console type="0" region="0"/>
+// </game>
+fn parse_xml(xml: &str) -> Vec<(u32, GameInfo)> {
+    let mut games = vec![];
+    for game in xml.split("<game>").skip(1) {
+        let game = game.split("</game>").next().unwrap_or("");
+        let crc = tag_attr(game, "rom", "crc32").and_then(|v| u32::from_str_radix(v, 16).ok());
+        let mapper = tag_attr(game, "pcb", "mapper").and_then(|v| v.parse().ok());
+        let (Some(crc), Some(mapper)) = (crc, mapper) else {
+            warn!("invalid game entry in {}", GAME_DB);
+            continue;
+        };
+        let submapper = tag_attr(game, "pcb", "submapper")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let ram_size = |tag| tag_attr(game, tag, "size").and_then(|v| v.parse::<usize>().ok());
+        let prg_ram_size = match (ram_size("prgram"), ram_size("prgnvram")) {
+            (None, None) => Some(0),
+            (ram, nvram) => Some(ram.unwrap_or(0) + nvram.unwrap_or(0)),
+        };
+        // 0: NTSC, 1: PAL, 2: 両対応, 3: Dendy
+        let region = match tag_attr(game, "console", "region") {
+            Some("1") => Some(Region::PAL),
+            Some("3") => Some(Region::DENDY),
+            Some(_) => Some(Region::NTSC),
+            None => None,
+        };
+        games.push((
+            crc,
+            GameInfo {
+                mapper: mapper,
+                submapper: submapper,
+                prg_ram_size: prg_ram_size,
+                region: region,
+            },
+        ));
+    }
+    games
+}
+
+// <tag ... name="value" ...> の value
+fn tag_attr<'a>(xml: &'a str, tag: &str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{} ", tag))?;
+    let element = &xml[start..start + xml[start..].find('>')?];
+    let pattern = format!(" {}=\"", name);
+    let value = &element[element.find(&pattern)? + pattern.len()..];
+    Some(&value[..value.find('"')?])
+}
+
+// CRC-32 (ZIPなどと同じ)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,213 @@
+use crate::joypad::{InputDevice, JoypadButton};
+use log::{info, warn};
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use sdl2::joystick::{HatState, Joystick};
+use sdl2::{GameControllerSubsystem, JoystickSubsystem, Sdl};
+
+// SDLの内蔵データベースにないパッドは、このファイルから対応を読む
+// see: https://github.com/gabomdq/SDL_GameControllerDB
+const CONTROLLER_DB: &str = "gamecontrollerdb.txt";
+
+enum Device {
+    // データベースで対応がわかるパッド
+    Controller(GameController),
+    // わからないパッドは、ボタン0-3, ハット0, 軸0/1 をそのまま使う
+    Joystick(Joystick),
+}
+
+struct Pad {
+    device: Device,
+    // 0: 1P .. 3: 4P
+    player: usize,
+    status: JoypadButton,
+}
+
+// 接続中のゲームパッド。抜き差しはイベントで追いかける
+pub struct Gamepads {
+    controller: Option<GameControllerSubsystem>,
+    joystick: Option<JoystickSubsystem>,
+    pads: Vec<Pad>,
+    // パッドを割り当てるプレイヤーの順番
+    players: Vec<usize>,
+    // アナログスティックを十字キーとみなす閾値
+    deadzone: i16,
+}
+
+impl Gamepads {
+    pub fn new(sdl_context: &Sdl, players: Vec<usize>, deadzone: i16) -> Self {
+        // パッドが使えなくても、キーボードだけで動かす
+        let controller = sdl_context
+            .game_controller()
+            .map_err(|e| warn!("game controller is not available: {}", e))
+            .ok();
+        let joystick = sdl_context
+            .joystick()
+            .map_err(|e| warn!("joystick is not available: {}", e))
+            .ok();
+        if let Some(controller) = &controller {
+            if let Ok(n) = controller.load_mappings(CONTROLLER_DB) {
+                info!("{} controller mappings loaded from {}", n, CONTROLLER_DB);
+            }
+        }
+        // 起動時につながっているパッドも JoyDeviceAdded で届く
+        Gamepads {
+            controller,
+            joystick,
+            pads: vec![],
+            players,
+            deadzone,
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &Event, ports: &mut [Box<dyn InputDevice>; 2]) {
+        match *event {
+            Event::JoyDeviceAdded { which, .. } => self.connect(which),
+            Event::JoyDeviceRemoved { which, .. } => self.disconnect(which, ports),
+            _ => { /* do nothing */ }
+        }
+    }
+
+    // フレームごとにパッドの状態を読んで、変わったボタンだけポートに伝える
+    // (キーボードの入力を上書きしないため)
+    pub fn update(&mut self, ports: &mut [Box<dyn InputDevice>; 2]) {
+        for pad in self.pads.iter_mut() {
+            let status = read_status(&pad.device, self.deadzone);
+            set_status(ports, pad.player, pad.status ^ status, status);
+            pad.status = status;
+        }
+    }
+
+    fn connect(&mut self, index: u32) {
+        let device = match (&self.controller, &self.joystick) {
+            (Some(controller), _) if controller.is_game_controller(index) => {
+                controller.open(index).map(Device::Controller).ok()
+            }
+            (_, Some(joystick)) => joystick.open(index).map(Device::Joystick).ok(),
+            _ => None,
+        };
+        let Some(device) = device else {
+            warn!("failed to open gamepad {}", index);
+            return;
+        };
+
+        let id = instance_id(&device);
+        if self.pads.iter().any(|pad| instance_id(&pad.device) == id) {
+            return;
+        }
+        let player = self
+            .players
+            .iter()
+            .find(|player| self.pads.iter().all(|pad| pad.player != **player));
+        match player {
+            Some(&player) => {
+                info!("gamepad connected: {} => {}P", name(&device), player + 1);
+                self.pads.push(Pad {
+                    device,
+                    player,
+                    status: JoypadButton::empty(),
+                });
+            }
+            None => warn!("no controller port left for {}", name(&device)),
+        }
+    }
+
+    fn disconnect(&mut self, id: u32, ports: &mut [Box<dyn InputDevice>; 2]) {
+        if let Some(i) = self
+            .pads
+            .iter()
+            .position(|pad| instance_id(&pad.device) == id)
+        {
+            let pad = self.pads.remove(i);
+            info!("gamepad disconnected: {}P", pad.player + 1);
+            // 押しっぱなしにならないように離す
+            set_status(ports, pad.player, pad.status, JoypadButton::empty());
+        }
+    }
+}
+
+// プレイヤー => (ポート, ポートの中のコントローラー)。3P/4PはFour Scoreのときだけ
+fn set_status(
+    ports: &mut [Box<dyn InputDevice>; 2],
+    player: usize,
+    changed: JoypadButton,
+    status: JoypadButton,
+) {
+    for button in changed.iter() {
+        ports[player % 2].set_controller_button_pressed_status(
+            player / 2,
+            button,
+            status.contains(button),
+        );
+    }
+}
+
+fn read_status(device: &Device, deadzone: i16) -> JoypadButton {
+    let mut status = JoypadButton::empty();
+    match device {
+        Device::Controller(controller) => {
+            // ファミコンのボタンの並び (左がB、右がA) に合わせる
+            status.set(
+                JoypadButton::BUTTON_B,
+                controller.button(Button::A) || controller.button(Button::X),
+            );
+            status.set(
+                JoypadButton::BUTTON_A,
+                controller.button(Button::B) || controller.button(Button::Y),
+            );
+            status.set(JoypadButton::SELECT, controller.button(Button::Back));
+            status.set(JoypadButton::START, controller.button(Button::Start));
+            status.set(JoypadButton::UP, controller.button(Button::DPadUp));
+            status.set(JoypadButton::DOWN, controller.button(Button::DPadDown));
+            status.set(JoypadButton::LEFT, controller.button(Button::DPadLeft));
+            status.set(JoypadButton::RIGHT, controller.button(Button::DPadRight));
+            status |= stick(
+                controller.axis(Axis::LeftX),
+                controller.axis(Axis::LeftY),
+                deadzone,
+            );
+        }
+        Device::Joystick(joystick) => {
+            let button = |i| joystick.button(i).unwrap_or(false);
+            status.set(JoypadButton::BUTTON_B, button(0));
+            status.set(JoypadButton::BUTTON_A, button(1));
+            status.set(JoypadButton::SELECT, button(2));
+            status.set(JoypadButton::START, button(3));
+            let hat = joystick.hat(0).unwrap_or(HatState::Centered) as u8;
+            status.set(JoypadButton::UP, hat & HatState::Up as u8 != 0);
+            status.set(JoypadButton::DOWN, hat & HatState::Down as u8 != 0);
+            status.set(JoypadButton::LEFT, hat & HatState::Left as u8 != 0);
+            status.set(JoypadButton::RIGHT, hat & HatState::Right as u8 != 0);
+            status |= stick(
+                joystick.axis(0).unwrap_or(0),
+                joystick.axis(1).unwrap_or(0),
+                deadzone,
+            );
+        }
+    }
+    status
+}
+
+// アナログスティックを十字キーにする
+fn stick(x: i16, y: i16, deadzone: i16) -> JoypadButton {
+    let mut status = JoypadButton::empty();
+    status.set(JoypadButton::LEFT, x < -deadzone);
+    status.set(JoypadButton::RIGHT, x > deadzone);
+    status.set(JoypadButton::UP, y < -deadzone);
+    status.set(JoypadButton::DOWN, y > deadzone);
+    status
+}
+
+fn instance_id(device: &Device) -> u32 {
+    match device {
+        Device::Controller(controller) => controller.instance_id(),
+        Device::Joystick(joystick) => joystick.instance_id(),
+    }
+}
+
+fn name(device: &Device) -> String {
+    match device {
+        Device::Controller(controller) => controller.name(),
+        Device::Joystick(joystick) => joystick.name(),
+    }
+}
@@ -16,6 +16,38 @@ bitflags! {
   }
 }
 
+// コントローラーポートにつなぐ機器
+// see: https://www.nesdev.org/wiki/Input_devices
+pub trait InputDevice {
+    // $4016 への書き込み (bit0: ストローブ, bit1-2: 拡張端子の出力)。両方のポートに届く。
+    fn write(&mut self, data: u8);
+    // $4016/$4017 の読み込み。機器が出力するのは下位5bitだけ。
+    fn read(&mut self) -> u8;
+    // フロントエンドからのボタンの状態。ボタンのない機器は無視する。
+    fn set_button_pressed_status(&mut self, _button: JoypadButton, _value: bool) {}
+    // 複数のコントローラーをまとめた機器用 (0: 1つ目, 1: 2つ目)
+    fn set_controller_button_pressed_status(
+        &mut self,
+        controller: usize,
+        button: JoypadButton,
+        value: bool,
+    ) {
+        if controller == 0 {
+            self.set_button_pressed_status(button, value);
+        }
+    }
+}
+
+// 何もつながっていないポート
+pub struct Unplugged;
+
+impl InputDevice for Unplugged {
+    fn write(&mut self, _data: u8) {}
+    fn read(&mut self) -> u8 {
+        0
+    }
+}
+
 pub struct Joypad {
     strobe: bool,
     button_index: u8,
@@ -30,15 +62,18 @@ impl Joypad {
             button_status: JoypadButton::from_bits_truncate(0),
         }
     }
+}
 
-    pub fn write(&mut self, data: u8) {
+impl InputDevice for Joypad {
+    fn write(&mut self, data: u8) {
         self.strobe = data & 1 == 1;
         if self.strobe {
             self.button_index = 0
         }
     }
 
-    pub fn read(&mut self) -> u8 {
+    fn read(&mut self) -> u8 {
+        // 8個読み終わったら、純正のコントローラーは1を返し続ける
         if self.button_index > 7 {
             return 1;
         }
@@ -52,7 +87,68 @@ impl Joypad {
         response
     }
 
-    pub fn set_button_pressed_status(&mut self, button: JoypadButton, value: bool) {
+    fn set_button_pressed_status(&mut self, button: JoypadButton, value: bool) {
         self.button_status.set(button, value)
     }
 }
+
+// Four Score (4人用アダプター)。両方のポートにつなぎ、1ポートで2人分を読む
+//   $4016: 1P, 3P, 署名 / $4017: 2P, 4P, 署名
+// see: https://www.nesdev.org/wiki/Four_player_adapters
+pub struct FourScore {
+    strobe: bool,
+    index: u8,
+    button_status: [JoypadButton; 2],
+    // 読み込み順の署名。標準の読み込みルーチンだと$10 ($4016) / $20 ($4017) になる
+    signature: u8,
+}
+
+impl FourScore {
+    // port: 0 => $4016, 1 => $4017
+    pub fn new(port: usize) -> Self {
+        FourScore {
+            strobe: false,
+            index: 0,
+            button_status: [JoypadButton::from_bits_truncate(0); 2],
+            signature: if port == 0 { 0x08 } else { 0x04 },
+        }
+    }
+}
+
+impl InputDevice for FourScore {
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.index = 0
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let response = match self.index {
+            0..=7 => self.button_status[0].bits() >> self.index,
+            8..=15 => self.button_status[1].bits() >> (self.index - 8),
+            16..=23 => self.signature >> (self.index - 16),
+            // 24個読み終わったら、以降は0とする
+            _ => return 0,
+        } & 1;
+        if !self.strobe && !unsafe { IN_TRACE } {
+            self.index += 1;
+        }
+        response
+    }
+
+    fn set_button_pressed_status(&mut self, button: JoypadButton, value: bool) {
+        self.set_controller_button_pressed_status(0, button, value)
+    }
+
+    fn set_controller_button_pressed_status(
+        &mut self,
+        controller: usize,
+        button: JoypadButton,
+        value: bool,
+    ) {
+        if let Some(status) = self.button_status.get_mut(controller) {
+            status.set(button, value)
+        }
+    }
+}
@@ -3,8 +3,11 @@ mod bus;
 mod cartridge;
 mod cpu;
 mod frame;
+mod gamedb;
+mod gamepad;
 mod joypad;
 mod mapper;
+mod ntsc;
 mod opscodes;
 mod palette;
 mod ppu;
@@ -12,22 +15,25 @@ mod render;
 mod rom;
 use crate::cpu::{trace, IN_TRACE};
 
-use self::bus::{Bus, Mem};
+use self::bus::{Bus, Mem, RamInit, ResetKind};
 use self::cpu::CPU;
 
 use apu::NesAPU;
 use cartridge::load_rom;
 use cartridge::test::{alter_ego_rom, mario_rom, test_rom};
 use frame::{show_tile, Frame};
-use joypad::Joypad;
+use gamepad::Gamepads;
+use joypad::{FourScore, InputDevice, Joypad, Unplugged};
 use log::{debug, info, log_enabled, trace, Level};
 use mapper::{create_mapper, Mapper, Mapper0, Mapper1, Mapper2};
+use ntsc::{NtscFilter, NtscPreset};
 use once_cell::sync::Lazy;
+use palette::{load_palette, NtscParams, Palette, PALETTE};
 use ppu::NesPPU;
 use rand::Rng;
-use rom::Rom;
+use rom::{Region, Rom};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::EventPump;
@@ -40,6 +46,8 @@ use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 static mut MAPPER: Lazy<Box<dyn Mapper>> = Lazy::new(|| create_mapper(Rom::empty()));
+static mut REGION: Region = Region::NTSC;
+static mut RAM_INIT: RamInit = RamInit::Zeros;
 
 fn main() {
     env_logger::builder()
@@ -54,6 +62,36 @@ fn main() {
         .format_timestamp(None)
         .init();
 
+    let args: Vec<String> = std::env::args().collect();
+
+    // 電源投入時のRAMの中身 (--ram-init zeros|ff|random|random:<seed>|console)
+    if let Some(i) = args.iter().position(|arg| arg == "--ram-init") {
+        let ram_init = args
+            .get(i + 1)
+            .and_then(|arg| RamInit::parse(arg))
+            .expect("--ram-init requires zeros, ff, random, random:<seed> or console");
+        // 同じ中身で再現できるように、シードを表示しておく
+        // (ログの設定によらず見えるように、標準出力に出す)
+        if let RamInit::Random(seed) = ram_init {
+            println!("--ram-init random:{}", seed);
+        }
+        unsafe { RAM_INIT = ram_init };
+    }
+
+    // ベンチマーク (--bench <フレーム数> [ROM])。画面を出さずに実行し、FPSを表示する
+    if let Some(i) = args.iter().position(|arg| arg == "--bench") {
+        let frames = args
+            .get(i + 1)
+            .and_then(|arg| arg.parse().ok())
+            .filter(|frames| *frames > 0)
+            .expect("--bench requires the number of frames (1 or more)");
+        let rom = match args.get(i + 2) {
+            Some(path) => load_rom(path),
+            None => mario_rom(),
+        };
+        run_benchmark(rom, frames);
+    }
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
@@ -75,15 +113,79 @@ fn main() {
         .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
         .unwrap();
 
+    // キー => (ポート, ボタン)
     let mut key_map = HashMap::new();
-    key_map.insert(Keycode::Down, joypad::JoypadButton::DOWN);
-    key_map.insert(Keycode::Up, joypad::JoypadButton::UP);
-    key_map.insert(Keycode::Right, joypad::JoypadButton::RIGHT);
-    key_map.insert(Keycode::Left, joypad::JoypadButton::LEFT);
-    key_map.insert(Keycode::Space, joypad::JoypadButton::SELECT);
-    key_map.insert(Keycode::Return, joypad::JoypadButton::START);
-    key_map.insert(Keycode::A, joypad::JoypadButton::BUTTON_A);
-    key_map.insert(Keycode::S, joypad::JoypadButton::BUTTON_B);
+    key_map.insert(Keycode::Down, (0, joypad::JoypadButton::DOWN));
+    key_map.insert(Keycode::Up, (0, joypad::JoypadButton::UP));
+    key_map.insert(Keycode::Right, (0, joypad::JoypadButton::RIGHT));
+    key_map.insert(Keycode::Left, (0, joypad::JoypadButton::LEFT));
+    key_map.insert(Keycode::Space, (0, joypad::JoypadButton::SELECT));
+    key_map.insert(Keycode::Return, (0, joypad::JoypadButton::START));
+    key_map.insert(Keycode::A, (0, joypad::JoypadButton::BUTTON_A));
+    key_map.insert(Keycode::S, (0, joypad::JoypadButton::BUTTON_B));
+    // 2P
+    key_map.insert(Keycode::K, (1, joypad::JoypadButton::DOWN));
+    key_map.insert(Keycode::I, (1, joypad::JoypadButton::UP));
+    key_map.insert(Keycode::L, (1, joypad::JoypadButton::RIGHT));
+    key_map.insert(Keycode::J, (1, joypad::JoypadButton::LEFT));
+    key_map.insert(Keycode::G, (1, joypad::JoypadButton::SELECT));
+    key_map.insert(Keycode::H, (1, joypad::JoypadButton::START));
+    key_map.insert(Keycode::M, (1, joypad::JoypadButton::BUTTON_A));
+    key_map.insert(Keycode::N, (1, joypad::JoypadButton::BUTTON_B));
+
+    // ゲームパッド (--pad-ports <ポートの順番> でつないだ順に割り当てるポート、
+    // --deadzone <0-32767> でアナログスティックの閾値)
+    let players = match args.iter().position(|arg| arg == "--pad-ports") {
+        Some(i) => args
+            .get(i + 1)
+            .and_then(|arg| {
+                arg.split(',')
+                    .map(|port| port.parse::<usize>().ok().filter(|p| (1..=4).contains(p)))
+                    .map(|port| port.map(|p| p - 1))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .expect("--pad-ports requires a list of ports 1-4 (e.g. 2,1)"),
+        None => vec![0, 1, 2, 3],
+    };
+    let deadzone = match args.iter().position(|arg| arg == "--deadzone") {
+        Some(i) => args
+            .get(i + 1)
+            .and_then(|arg| arg.parse::<i16>().ok())
+            .filter(|deadzone| *deadzone >= 0)
+            .expect("--deadzone requires a number from 0 to 32767"),
+        None => 8000,
+    };
+    let mut gamepads = Gamepads::new(&sdl_context, players, deadzone);
+
+    // パレット (--palette <.palファイル|ntsc[:<パラメーター>]> で選択、Pキーで切り替え)
+    let mut palettes = vec![Palette::system(), Palette::ntsc(&NtscParams::new())];
+    let mut palette_index = 0;
+    if let Some(i) = args.iter().position(|arg| arg == "--palette") {
+        match args.get(i + 1).map(|arg| arg.as_str()) {
+            Some("ntsc") => palette_index = 1,
+            Some(arg) if arg.starts_with("ntsc:") => {
+                let params = NtscParams::parse(&arg["ntsc:".len()..])
+                    .expect("--palette ntsc:<hue|sat|contrast|bright|gamma>=<value>,...");
+                palettes[1] = Palette::ntsc(&params);
+                palette_index = 1;
+            }
+            Some(path) => {
+                palettes.push(load_palette(path));
+                palette_index = palettes.len() - 1;
+            }
+            None => panic!("--palette requires a .pal file or ntsc"),
+        }
+    }
+    unsafe { *PALETTE = palettes[palette_index].clone() };
+
+    // NTSCフィルター (--filter composite|svideo|rgb で選択、Fキーで切り替え)
+    let mut ntsc_filter = NtscFilter::new(NtscPreset::Rgb);
+    if let Some(i) = args.iter().position(|arg| arg == "--filter") {
+        ntsc_filter.preset = args
+            .get(i + 1)
+            .and_then(|arg| NtscPreset::parse(arg))
+            .expect("--filter requires composite, svideo or rgb");
+    }
 
     let rom = load_rom("rom/Dragon Quest (Japan).nes");
 
@@ -104,55 +206,125 @@ fn main() {
         rom.mapper, rom.submapper, rom.screen_mirroring, rom.is_chr_ram
     );
 
-    unsafe {
-        *MAPPER = create_mapper(rom);
-    }
+    // 地域 (--region ntsc|pal|dendy で指定、なければヘッダーから)
+    let region = match args.iter().position(|arg| arg == "--region") {
+        Some(i) => args
+            .get(i + 1)
+            .and_then(|arg| Region::parse(arg))
+            .expect("--region requires ntsc, pal or dendy"),
+        None => rom.region,
+    };
+    unsafe { REGION = region };
 
     let mut now = Instant::now();
-    let interval = 1000 * 1000 * 1000 / 60;
+    let interval = (1000.0 * 1000.0 * 1000.0 / region.frame_rate()) as u128;
 
     let apu = NesAPU::new(&sdl_context);
-    let bus = Bus::new(
+    let mut bus = Bus::new(
         apu,
-        move |ppu: &NesPPU, joypad1: &mut Joypad, frame: &Frame| {
-            texture.update(None, &frame.data, 256 * 3).unwrap();
+        move |ppu: &NesPPU, ports: &mut [Box<dyn InputDevice>; 2], frame: &Frame| {
+            let mut reset_request = None;
+            ntsc_filter.apply(frame);
+            texture.update(None, &ntsc_filter.data, 256 * 3).unwrap();
 
             canvas.copy(&texture, None, None).unwrap();
 
             canvas.present();
 
             for event in event_pump.poll_iter() {
+                gamepads.handle_event(&event, ports);
                 match event {
                     Event::Quit { .. }
                     | Event::KeyDown {
                         keycode: Some(Keycode::Escape),
                         ..
                     } => std::process::exit(0),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::P),
+                        repeat: false,
+                        ..
+                    } => {
+                        palette_index = (palette_index + 1) % palettes.len();
+                        unsafe { *PALETTE = palettes[palette_index].clone() };
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F),
+                        repeat: false,
+                        ..
+                    } => {
+                        ntsc_filter.preset = ntsc_filter.preset.next();
+                    }
+                    // Rキーでリセット、Shift+Rで電源の入れ直し
+                    Event::KeyDown {
+                        keycode: Some(Keycode::R),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } => {
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            reset_request = Some(ResetKind::PowerCycle);
+                        } else {
+                            reset_request = Some(ResetKind::Soft);
+                        }
+                    }
                     Event::KeyDown { keycode, .. } => {
-                        if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                            joypad1.set_button_pressed_status(*key, true);
+                        if let Some((port, key)) =
+                            key_map.get(&keycode.unwrap_or(Keycode::Ampersand))
+                        {
+                            ports[*port].set_button_pressed_status(*key, true);
                         }
                     }
                     Event::KeyUp { keycode, .. } => {
-                        if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                            joypad1.set_button_pressed_status(*key, false);
+                        if let Some((port, key)) =
+                            key_map.get(&keycode.unwrap_or(Keycode::Ampersand))
+                        {
+                            ports[*port].set_button_pressed_status(*key, false);
                         }
                     }
                     _ => { /* do nothing */ }
                 }
             }
 
+            gamepads.update(ports);
+
             let time = now.elapsed().as_nanos();
             if time < interval {
                 sleep(Duration::from_nanos((interval - time) as u64));
             }
             now = Instant::now();
+            reset_request
         },
     );
 
+    // マッパーは電源投入時に作られる
+    bus.insert_cartridge(rom);
+
+    // 1ラインのスプライトの数の制限をなくす (--no-sprite-limit)
+    if args.iter().any(|arg| arg == "--no-sprite-limit") {
+        bus.set_remove_sprite_limit(true);
+    }
+
+    // コントローラーポートにつなぐ機器 (--port1 / --port2 joypad|none)
+    for (port, option) in ["--port1", "--port2"].iter().enumerate() {
+        if let Some(i) = args.iter().position(|arg| arg == option) {
+            let device: Box<dyn InputDevice> = match args.get(i + 1).map(|arg| arg.as_str()) {
+                Some("joypad") => Box::new(Joypad::new()),
+                Some("none") => Box::new(Unplugged),
+                _ => panic!("{} requires joypad or none", option),
+            };
+            bus.connect(port, device);
+        }
+    }
+
+    // 3P/4P (--four-score で両方のポートに Four Score をつなぐ)
+    if args.iter().any(|arg| arg == "--four-score") {
+        bus.connect(0, Box::new(FourScore::new(0)));
+        bus.connect(1, Box::new(FourScore::new(1)));
+    }
+
     let mut cpu = CPU::new(bus);
 
-    cpu.reset();
+    cpu.power_on();
     cpu.run_with_callback(move |cpu| {
         if log_enabled!(Level::Trace) {
             trace(cpu);
@@ -188,6 +360,42 @@ fn main() {
     */
 }
 
+fn run_benchmark(rom: Rom, frames: usize) {
+    // 音は出さない
+    std::env::set_var("SDL_AUDIODRIVER", "dummy");
+    let sdl_context = sdl2::init().unwrap();
+
+    unsafe { REGION = rom.region };
+
+    let apu = NesAPU::new(&sdl_context);
+    let start = Instant::now();
+    let mut count = 0;
+    let mut bus = Bus::new(
+        apu,
+        move |_ppu: &NesPPU, _ports: &mut [Box<dyn InputDevice>; 2], _frame: &Frame| {
+            count += 1;
+            if count == frames {
+                let elapsed = start.elapsed().as_secs_f64();
+                // 結果はログではなく、このコマンドの出力として標準出力に出す
+                println!(
+                    "{} frames in {:.3}s ({:.1} fps)",
+                    frames,
+                    elapsed,
+                    frames as f64 / elapsed
+                );
+                std::process::exit(0);
+            }
+            None
+        },
+    );
+
+    bus.insert_cartridge(rom);
+
+    let mut cpu = CPU::new(bus);
+    cpu.power_on();
+    cpu.run_with_callback(|_| {});
+}
+
 fn find_sdl_gl_driver() -> Option<u32> {
     for (index, item) in sdl2::render::drivers().enumerate() {
         if item.name == "opengl" {
@@ -1,40 +1,80 @@
+use crate::gamedb;
 use crate::rom::{Mirroring, Rom};
+use log::{info, warn};
 use std::fs::File;
 use std::io::{self, BufReader, Read, Write};
 
 pub fn create_mapper(rom: Rom) -> Box<dyn Mapper> {
     let mut mapper: Box<dyn Mapper> = match rom.mapper {
         0 => Box::new(Mapper0::new()),
-        1 => {
-            // DQ3, 4を動かすためのhack.
-            if rom.is_chr_ram {
-                Box::new(SxRom::new())
-            } else {
-                Box::new(Mapper1::new())
-            }
-        }
+        1 => Box::new(Mapper1::new(false)),
         2 => Box::new(Mapper2::new()),
         3 => Box::new(Mapper3::new()),
-        4 => Box::new(Mapper4::new()),
+        4 | 118 | 119 => Box::new(Mapper4::new(rom.mapper, rom.submapper)),
+        7 => Box::new(Mapper7::new(rom.submapper)),
+        9 => Box::new(Mapper9::new()),
+        10 => Box::new(Mapper10::new()),
+        11 => Box::new(Mapper11::new()),
+        21 | 22 | 23 | 25 => Box::new(Vrc4::new(rom.mapper, vrc_submapper(&rom))),
+        34 => Box::new(Mapper34::new(rom.submapper, rom.chr_rom.len())),
+        66 => Box::new(Mapper66::new()),
+        68 => Box::new(Mapper68::new()),
+        71 => Box::new(Mapper71::new(rom.submapper)),
+        79 => Box::new(Mapper79::new()),
+        87 => Box::new(Mapper87::new()),
+        140 => Box::new(Mapper140::new()),
+        155 => Box::new(Mapper1::new(true)),
         _ => panic!("not support mapper."),
     };
     mapper.set_rom(rom);
     return mapper;
 }
 
+// ネームテーブルの各1KB領域の参照先
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NameTable {
+    // VRAMのページ (0, 1: PPU内蔵, 2, 3: カートリッジ上の追加VRAM)
+    Vram(usize),
+    // CHR ROMの1KBバンク
+    ChrRom(usize),
+}
+
 pub trait Mapper: Send {
     fn set_rom(&mut self, rom: Rom);
     fn is_chr_ram(&mut self) -> bool;
     fn write(&mut self, addr: u16, data: u8);
+    // $4020-$5FFF (拡張領域) への書き込み。ここにレジスタを持つマッパー(NINA-03/06など)はこちらを実装する。
+    fn write_expansion(&mut self, _addr: u16, _data: u8) {}
     fn mirroring(&self) -> Mirroring;
+    // ネームテーブルを自由に割り当てるマッパーはこちらを実装する。
+    fn name_table(&self, name_table: usize) -> NameTable {
+        NameTable::Vram(self.mirroring().vram_page(name_table))
+    }
+    // NameTable::ChrRomを返すマッパーはこちらでCHR ROMの内容を返す。
+    fn read_chr_name_table(&self, _bank: usize, _addr: u16) -> u8 {
+        0
+    }
     fn write_prg_ram(&mut self, addr: u16, data: u8);
     fn read_prg_ram(&self, addr: u16) -> u8;
+    // PRG RAMが何も出力しない (オープンバスになる) 場合はこちらでtrueを返す。
+    fn is_prg_ram_open_bus(&self, _addr: u16) -> bool {
+        false
+    }
     fn load_prg_ram(&mut self, raw: &Vec<u8>);
     fn read_prg_rom(&self, addr: u16) -> u8;
     fn write_chr_rom(&mut self, addr: u16, value: u8);
     fn read_chr_rom(&self, addr: u16) -> u8;
-    fn scanline(&mut self, scanline: usize, show_background: bool);
-    fn is_irq(&mut self) -> bool;
+    // PPUによるパターンの読み込み。読み込みをトリガーにバンクを切り替えるマッパー(MMC2/MMC4)はこちらを実装する。
+    fn fetch_chr(&mut self, addr: u16) -> u8 {
+        self.read_chr_rom(addr)
+    }
+    // PPUのアドレスバスへのアクセス。A12の立ち上がりでスキャンラインを数えるマッパー(MMC3など)はこちらを実装する。
+    // ppu_cyclesはPPUの起動からのドット数。
+    fn ppu_bus_address(&mut self, _addr: u16, _ppu_cycles: usize) {}
+    // CPUサイクルごとに処理が必要なマッパー(VRC4のIRQなど)はこちらを実装する。
+    fn tick(&mut self, _cycles: u8) {}
+    // IRQ出力。CPUがマッパーのレジスタで解除するまで立ったまま。
+    fn is_irq(&self) -> bool;
 }
 
 pub struct Mapper0 {
@@ -75,15 +115,31 @@ impl Mapper for Mapper0 {
     fn read_chr_rom(&self, addr: u16) -> u8 {
         self.rom.chr_rom[addr as usize]
     }
-    fn scanline(&mut self, scanline: usize, show_background: bool) {}
-    fn is_irq(&mut self) -> bool {
+    fn is_irq(&self) -> bool {
         false
     }
 }
 
+// MMC1のボードの種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mmc1Board {
+    Standard,
+    // CHRバンクのbit4でPRG RAMを無効化
+    Snrom,
+    // 16KB PRG RAM: CHRバンクのbit3でPRG RAMのバンクを選択
+    Sorom,
+    // 512KB PRG ROM: CHRバンクのbit4でPRG ROMの256KB単位のバンクを選択
+    Surom,
+    // Surom + 32KB PRG RAM: CHRバンクのbit2-3でPRG RAMのバンクを選択
+    Sxrom,
+}
+
 pub struct Mapper1 {
     rom: Rom,
     prg_ram: Vec<u8>,
+    board: Mmc1Board,
+    // MMC1A (Mapper 155): PRG RAMの無効化ビットがなく、PRGバンクのbit3が固定バンクにも効く
+    mmc1a: bool,
 
     shift_register: u8,
     shift_count: u8,
@@ -92,13 +148,19 @@ pub struct Mapper1 {
     chr_bank0: u8,
     chr_bank1: u8,
     prg_bank: u8,
+
+    // 連続したサイクルでの書き込みを無視するため
+    cycles: usize,
+    last_write_cycles: Option<usize>,
 }
 
 impl Mapper1 {
-    pub fn new() -> Self {
+    pub fn new(mmc1a: bool) -> Self {
         Mapper1 {
             rom: Rom::empty(),
-            prg_ram: vec![0xFF; 8192],
+            prg_ram: vec![],
+            board: Mmc1Board::Standard,
+            mmc1a: mmc1a,
             shift_register: 0x10,
             shift_count: 0,
 
@@ -106,18 +168,96 @@ impl Mapper1 {
             chr_bank0: 0,
             chr_bank1: 0,
             prg_bank: 0,
+
+            cycles: 0,
+            last_write_cycles: None,
         }
     }
     fn reset(&mut self) {
         self.shift_register = 0x10;
         self.shift_count = 0;
     }
+
+    // PRG RAMのサイズとsubmapperは、NES2.0のヘッダーか、ゲームのデータベースから (Rom::new)
+    fn detect_board(rom: &Rom) -> Mmc1Board {
+        // NES2.0の(非推奨の)submapperがあればそれに従う
+        match rom.submapper {
+            1 => return Mmc1Board::Surom,
+            2 => return Mmc1Board::Sorom,
+            4 => return Mmc1Board::Sxrom,
+            _ => {}
+        }
+        let chr_8k = rom.chr_rom.len() <= 8 * 1024;
+        // サイズが推測のとき (iNES 1.0) は、PRG RAMのないSGROMと区別できないので、
+        // バッテリーがある場合だけSNROMとみなす
+        let has_prg_ram = if rom.prg_ram_size_known {
+            rom.prg_ram_size > 0
+        } else {
+            rom.has_battery
+        };
+        if rom.prg_ram_size >= 32 * 1024 {
+            Mmc1Board::Sxrom
+        } else if rom.prg_ram_size >= 16 * 1024 {
+            Mmc1Board::Sorom
+        } else if rom.prg_rom.len() > 256 * 1024 {
+            Mmc1Board::Surom
+        } else if chr_8k && has_prg_ram {
+            Mmc1Board::Snrom
+        } else {
+            Mmc1Board::Standard
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        if !self.mmc1a && self.prg_bank & 0x10 != 0 {
+            return false;
+        }
+        if self.board == Mmc1Board::Snrom && self.chr_bank0 & 0x10 != 0 {
+            return false;
+        }
+        !self.prg_ram.is_empty()
+    }
+
+    fn prg_ram_addr(&self, addr: u16) -> usize {
+        let bank_len = 8 * 1024 as usize;
+        let bank = match self.board {
+            Mmc1Board::Sorom => (self.chr_bank0 as usize >> 3) & 0x01,
+            Mmc1Board::Sxrom => (self.chr_bank0 as usize >> 2) & 0x03,
+            _ => 0,
+        };
+        (addr as usize - 0x6000 + bank_len * bank) % self.prg_ram.len()
+    }
+
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        let bank_len = 4 * 1024 as usize;
+        let bank_max = self.rom.chr_rom.len() / bank_len;
+        let bank = match ((self.control & 0x10) >> 4, addr) {
+            // 一度に 8 KB を切り替え
+            (0, 0x0000..=0x0FFF) => self.chr_bank0 & 0x1E,
+            (0, _) => (self.chr_bank0 & 0x1E) + 1,
+            // 2 つの別々の 4 KB バンクを切り替え
+            (_, 0x0000..=0x0FFF) => self.chr_bank0 & 0x1F,
+            (_, _) => self.chr_bank1 & 0x1F,
+        };
+        (addr as usize & 0x0FFF) + bank_len * (bank as usize % bank_max)
+    }
 }
 
 impl Mapper for Mapper1 {
     fn write(&mut self, addr: u16, data: u8) {
+        // 連続したサイクルの書き込み (INCなどのリードモディファイライト命令) は、2回目が無視される
+        let consecutive = match self.last_write_cycles {
+            Some(cycles) => self.cycles - cycles <= 1,
+            None => false,
+        };
+        self.last_write_cycles = Some(self.cycles);
+        if consecutive {
+            return;
+        }
+
         if data & 0x80 != 0 {
             self.reset();
+            self.control = self.control | 0x0C;
             return;
         }
         self.shift_register = self.shift_register >> 1;
@@ -138,9 +278,11 @@ impl Mapper for Mapper1 {
 
     fn mirroring(&self) -> Mirroring {
         match self.control & 0x03 {
+            0 => Mirroring::ONE_SCREEN_LOWER,
+            1 => Mirroring::ONE_SCREEN_UPPER,
             2 => Mirroring::VERTICAL,
             3 => Mirroring::HORIZONTAL,
-            _ => panic!("not support mirroring mode."),
+            _ => panic!("can't be"),
         }
     }
 
@@ -148,69 +290,49 @@ impl Mapper for Mapper1 {
         let bank_len = 16 * 1024 as usize;
         let bank_max = self.rom.prg_rom.len() / bank_len;
 
-        match (self.control & 0x0C) >> 2 {
-            0 | 1 => {
-                // バンク番号の下位ビットを無視して、32 KB を $8000 に切り替えます。
-                let bank = self.prg_bank & 0x1E;
-                self.rom.prg_rom[(addr as usize - 0x8000 + bank_len * bank as usize) as usize]
-            }
-            2 => {
-                // 最初のバンクを $8000 に固定し、16 KB バンクを $C000 に切り替えます。
-                match addr {
-                    0x8000..=0xBFFF => self.rom.prg_rom[addr as usize - 0x8000],
-                    0xC000..=0xFFFF => {
-                        let bank = self.prg_bank & 0x1F;
-                        self.rom.prg_rom
-                            [(addr as usize - 0xC000 + bank_len * bank as usize) as usize]
-                    }
-                    _ => panic!("can't be"),
-                }
-            }
-            3 => {
-                // 最後のバンクを $C000 に固定し、16 KB バンクを $8000 に切り替えます)
-                match addr {
-                    0x8000..=0xBFFF => {
-                        let bank = self.prg_bank & 0x1F;
-                        self.rom.prg_rom
-                            [(addr as usize - 0x8000 + bank_len * bank as usize) as usize]
-                    }
-                    0xC000..=0xFFFF => {
-                        self.rom.prg_rom[addr as usize - 0xC000 + bank_len * (bank_max - 1)]
-                    }
-                    _ => panic!("can't be"),
-                }
-            }
-            _ => panic!("can't be"),
-        }
+        // SUROM/SXROM: 256KB単位のバンク
+        let outer = match self.board {
+            Mmc1Board::Surom | Mmc1Board::Sxrom => self.chr_bank0 & 0x10,
+            _ => 0,
+        };
+        let bank = self.prg_bank & 0x0F;
+        // MMC1Aは固定バンクもbit3で切り替わる
+        let (first_bank, last_bank) = if self.mmc1a {
+            (bank & 0x08, (bank & 0x08) | 0x07)
+        } else {
+            (0x00, 0x0F)
+        };
+
+        let bank = match ((self.control & 0x0C) >> 2, addr) {
+            // バンク番号の下位ビットを無視して、32 KB を $8000 に切り替えます。
+            (0 | 1, 0x8000..=0xBFFF) => bank & 0x0E,
+            (0 | 1, _) => (bank & 0x0E) + 1,
+            // 最初のバンクを $8000 に固定し、16 KB バンクを $C000 に切り替えます。
+            (2, 0x8000..=0xBFFF) => first_bank,
+            (2, _) => bank,
+            // 最後のバンクを $C000 に固定し、16 KB バンクを $8000 に切り替えます)
+            (_, 0x8000..=0xBFFF) => bank,
+            (_, _) => last_bank,
+        };
+        let bank = (outer | bank) as usize % bank_max;
+        self.rom.prg_rom[(addr as usize & 0x3FFF) + bank_len * bank]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        let mirror_addr = self.chr_rom_addr(addr);
+        self.rom.chr_rom[mirror_addr] = value;
     }
 
     fn read_chr_rom(&self, addr: u16) -> u8 {
-        let bank_len = 4 * 1024 as usize;
-        match (self.control & 0x10) >> 4 {
-            0 => {
-                // 一度に 8 KB を切り替え
-                let bank = self.chr_bank0 & 0x1F;
-                self.rom.chr_rom[addr as usize + bank_len * bank as usize]
-            }
-            1 => {
-                // 2 つの別々の 4 KB バンクを切り替え
-                match addr {
-                    0x0000..=0x0FFF => {
-                        let bank = self.chr_bank0 & 0x1F;
-                        self.rom.chr_rom[addr as usize + bank_len * bank as usize]
-                    }
-                    0x1000..=0x1FFF => {
-                        let bank = self.chr_bank1 & 0x1F;
-                        self.rom.chr_rom[addr as usize - 0x1000 + bank_len * bank as usize]
-                    }
-                    _ => panic!("can't be"),
-                }
-            }
-            _ => panic!("can't be"),
-        }
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
     }
 
     fn set_rom(&mut self, rom: Rom) {
+        self.board = Mapper1::detect_board(&rom);
+        if rom.submapper == 3 {
+            self.mmc1a = true;
+        }
+        self.prg_ram = vec![0xFF; rom.prg_ram_size];
         self.load_prg_ram(&rom.save_data);
         self.rom = rom;
     }
@@ -219,182 +341,36 @@ impl Mapper for Mapper1 {
     }
 
     fn write_prg_ram(&mut self, addr: u16, data: u8) {
-        self.prg_ram[addr as usize - 0x6000] = data;
-    }
-
-    fn read_prg_ram(&self, addr: u16) -> u8 {
-        self.prg_ram[addr as usize - 0x6000]
-    }
-
-    fn load_prg_ram(&mut self, raw: &Vec<u8>) {}
-
-    fn write_chr_rom(&mut self, addr: u16, value: u8) {}
-
-    fn scanline(&mut self, scanline: usize, show_background: bool) {}
-
-    fn is_irq(&mut self) -> bool {
-        false
-    }
-}
-
-// SxROM (SUROM) (Mapper1のsubmapper)
-pub struct SxRom {
-    pub rom: Rom,
-    prg_ram: Vec<u8>,
-
-    shift_register: u8,
-    shift_count: u8,
-
-    control: u8,
-    chr_bank0: u8,
-    chr_bank1: u8,
-    prg_bank: u8,
-}
-
-impl SxRom {
-    pub fn new() -> Self {
-        SxRom {
-            rom: Rom::empty(),
-            prg_ram: vec![0xFF; 8192],
-            shift_register: 0x10,
-            shift_count: 0,
-
-            control: 0x0C,
-            chr_bank0: 0,
-            chr_bank1: 0,
-            prg_bank: 0,
-        }
-    }
-
-    fn reset(&mut self) {
-        self.shift_register = 0x10;
-        self.shift_count = 0;
-    }
-}
-
-impl Mapper for SxRom {
-    fn set_rom(&mut self, rom: Rom) {
-        self.load_prg_ram(&rom.save_data);
-        self.rom = rom;
-    }
-    fn is_chr_ram(&mut self) -> bool {
-        self.rom.is_chr_ram
-    }
-    fn write(&mut self, addr: u16, data: u8) {
-        if data & 0x80 != 0 {
-            self.reset();
+        if !self.prg_ram_enabled() {
             return;
         }
+        let mirror_addr = self.prg_ram_addr(addr);
+        self.prg_ram[mirror_addr] = data;
 
-        self.shift_register = self.shift_register >> 1;
-        self.shift_register = self.shift_register | ((data & 0x01) << 4);
-        self.shift_count += 1;
-
-        if self.shift_count == 5 {
-            match addr {
-                0x8000..=0x9FFF => self.control = self.shift_register,
-                0xA000..=0xBFFF => self.chr_bank0 = self.shift_register,
-                0xC000..=0xDFFF => self.chr_bank1 = self.shift_register,
-                0xE000..=0xFFFF => self.prg_bank = self.shift_register,
-                _ => panic!("can't be"),
-            }
-            self.reset();
-        }
-    }
-
-    fn mirroring(&self) -> Mirroring {
-        match self.control & 0x03 {
-            // 0: one-screen, lower bank;
-            // 1: one-screen, upper bank;
-            0 => Mirroring::VERTICAL, // FIXME ロックマン2のために追加。本来はone-screenの実装が必要。
-            2 => Mirroring::VERTICAL,
-            3 => Mirroring::HORIZONTAL,
-            _ => panic!("not support mirroring mode. {}", self.control & 0x03),
+        if self.rom.has_battery {
+            let mut file = File::create(self.rom.save_data_file.as_str()).unwrap();
+            file.write_all(&self.prg_ram).unwrap();
+            file.flush().unwrap();
         }
     }
 
-    fn write_prg_ram(&mut self, addr: u16, data: u8) {
-        self.prg_ram[addr as usize - 0x6000] = data;
-
-        // FIXME　保存処理
-        let mut file = File::create(self.rom.save_data_file.as_str()).unwrap();
-        file.write_all(&self.prg_ram).unwrap();
-        file.flush().unwrap();
-    }
-
     fn read_prg_ram(&self, addr: u16) -> u8 {
-        self.prg_ram[addr as usize - 0x6000]
-    }
-
-    fn load_prg_ram(&mut self, raw: &Vec<u8>) {
-        if raw.is_empty() {
-            return;
+        if !self.prg_ram_enabled() {
+            return 0;
         }
-        self.prg_ram = raw.to_vec()
+        self.prg_ram[self.prg_ram_addr(addr)]
     }
 
-    fn read_prg_rom(&self, addr: u16) -> u8 {
-        let bank_len = 16 * 1024 as usize;
-        let bank_max = self.rom.prg_rom.len() / bank_len;
-        let mut bank = self.prg_bank & 0x0F;
-        let mut first_bank = 0x00;
-        let mut last_bank = bank_max - 1;
-
-        if self.chr_bank0 & 0x10 != 0 {
-            bank = bank | 0x10;
-            first_bank = first_bank | 0x10;
-            last_bank = last_bank | 0x10;
-        } else {
-            bank = bank & 0x0F;
-            first_bank = first_bank & 0x0F;
-            last_bank = last_bank & 0x0F;
-        }
-
-        match (self.control & 0x0C) >> 2 {
-            0 | 1 => {
-                // バンク番号の下位ビットを無視して、32 KB を $8000 に切り替えます。
-                self.rom.prg_rom
-                    [(addr as usize - 0x8000 + bank_len * (bank & 0x1E) as usize) as usize]
-            }
-            2 => {
-                // 最初のバンクを $8000 に固定し、16 KB バンクを $C000 に切り替えます。
-                match addr {
-                    0x8000..=0xBFFF => {
-                        self.rom.prg_rom[addr as usize - 0x8000 + bank_len * first_bank]
-                    }
-                    0xC000..=0xFFFF => {
-                        self.rom.prg_rom
-                            [(addr as usize - 0xC000 + bank_len * bank as usize) as usize]
-                    }
-                    _ => panic!("can't be"),
-                }
-            }
-            3 => {
-                // 最後のバンクを $C000 に固定し、16 KB バンクを $8000 に切り替えます)
-                match addr {
-                    0x8000..=0xBFFF => {
-                        self.rom.prg_rom
-                            [(addr as usize - 0x8000 + bank_len * bank as usize) as usize]
-                    }
-                    0xC000..=0xFFFF => {
-                        self.rom.prg_rom[addr as usize - 0xC000 + bank_len * last_bank]
-                    }
-                    _ => panic!("can't be"),
-                }
-            }
-            _ => panic!("can't be"),
-        }
+    fn load_prg_ram(&mut self, raw: &Vec<u8>) {
+        let len = raw.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&raw[..len]);
     }
 
-    fn write_chr_rom(&mut self, addr: u16, value: u8) {
-        self.rom.chr_rom[addr as usize] = value
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
     }
 
-    fn read_chr_rom(&self, addr: u16) -> u8 {
-        self.rom.chr_rom[addr as usize]
-    }
-    fn scanline(&mut self, scanline: usize, show_background: bool) {}
-    fn is_irq(&mut self) -> bool {
+    fn is_irq(&self) -> bool {
         false
     }
 }
@@ -456,8 +432,7 @@ impl Mapper for Mapper2 {
     fn read_chr_rom(&self, addr: u16) -> u8 {
         self.rom.chr_rom[addr as usize]
     }
-    fn scanline(&mut self, scanline: usize, show_background: bool) {}
-    fn is_irq(&mut self) -> bool {
+    fn is_irq(&self) -> bool {
         false
     }
 }
@@ -508,15 +483,25 @@ impl Mapper for Mapper3 {
         let bank = self.bank_select & 0x03;
         self.rom.chr_rom[(addr as usize + bank_len * bank as usize) as usize]
     }
-    fn scanline(&mut self, scanline: usize, show_background: bool) {}
-    fn is_irq(&mut self) -> bool {
+    fn is_irq(&self) -> bool {
         false
     }
 }
 
+// MMC3 (Mapper 4) およびその派生ボード
+//   MMC6 (Mapper 4, submapper 1): 1KBの内蔵PRG RAM
+//   MMC3A (Mapper 4, submapper 4): 旧仕様のIRQ
+//   (iNES 1.0 のROMでは、submapperはゲームのデータベースから引く)
+//   TxSROM (Mapper 118): CHRバンクのbit7でネームテーブルを選択
+//   TQROM (Mapper 119): CHRバンクのbit6でCHR ROM/CHR RAMを選択
 pub struct Mapper4 {
     pub rom: Rom,
     prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    mmc6: bool,
+    mmc3a: bool,
+    txsrom: bool,
+    tqrom: bool,
     bank_select: u8,
     bank_data: [u8; 8],
     mirroring: u8,
@@ -526,31 +511,60 @@ pub struct Mapper4 {
     irq_enable: bool,
     irq_counter: u8,
     irq: bool,
+    last_a12_high: usize,
 }
 
 impl Mapper4 {
-    pub fn new() -> Self {
+    pub fn new(mapper: u8, submapper: u8) -> Self {
+        let mmc6 = mapper == 4 && submapper == 1;
         Mapper4 {
             rom: Rom::empty(),
-            prg_ram: vec![0xFF; 8192],
+            prg_ram: vec![0xFF; if mmc6 { 1024 } else { 8192 }],
+            chr_ram: vec![0; if mapper == 119 { 8192 } else { 0 }],
+            mmc6: mmc6,
+            mmc3a: mapper == 4 && submapper == 4,
+            txsrom: mapper == 118,
+            tqrom: mapper == 119,
             bank_select: 0,
             bank_data: [0; 8],
             mirroring: 0,
-            prg_ram_protect: 0,
+            // $A001を書き込まないゲームのために、PRG RAMは有効にしておく
+            prg_ram_protect: if mmc6 { 0 } else { 0x80 },
             irq_latch: 0,
             irq_reload: false,
             irq_enable: false,
             irq_counter: 0,
             irq: false,
+            last_a12_high: 0,
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        let counter = self.irq_counter;
+        let reload = self.irq_reload;
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        // MMC3A: カウンタが0から再ロードされた場合はIRQを発生させない ($C001による再ロードは除く)
+        let trigger = if self.mmc3a {
+            self.irq_counter == 0 && (counter != 0 || reload)
+        } else {
+            self.irq_counter == 0
+        };
+        if trigger && self.irq_enable {
+            self.irq = true;
         }
     }
-    fn chr_rom_addr(&self, addr: u16) -> usize {
-        let bank_len = 1 * 1024 as usize;
 
+    // 1KB単位のCHRバンク番号
+    fn chr_bank(&self, addr: u16) -> usize {
         let mode = self.bank_select & 0x80;
 
-        let r0_bank = self.bank_data[0] as usize;
-        let r1_bank = self.bank_data[1] as usize;
+        let r0_bank = (self.bank_data[0] & 0xFE) as usize;
+        let r1_bank = (self.bank_data[1] & 0xFE) as usize;
         let r2_bank = self.bank_data[2] as usize;
         let r3_bank = self.bank_data[3] as usize;
         let r4_bank = self.bank_data[4] as usize;
@@ -558,33 +572,61 @@ impl Mapper4 {
 
         match mode {
             0 => match addr {
-                // 0x0000..=0x03FF
-                // 0x0400..=0x07FF
-                0x0000..=0x07FF => addr as usize + r0_bank * bank_len,
-                // 0x0800..=0x0BFF
-                // 0x0C00..=0x0FFF
-                0x0800..=0x0FFF => (addr - 0x0800) as usize + r1_bank * bank_len,
-                0x1000..=0x13FF => (addr - 0x1000) as usize + r2_bank * bank_len,
-                0x1400..=0x17FF => (addr - 0x1400) as usize + r3_bank * bank_len,
-                0x1800..=0x1BFF => (addr - 0x1800) as usize + r4_bank * bank_len,
-                0x1C00..=0x1FFF => (addr - 0x1C00) as usize + r5_bank * bank_len,
+                0x0000..=0x03FF => r0_bank,
+                0x0400..=0x07FF => r0_bank + 1,
+                0x0800..=0x0BFF => r1_bank,
+                0x0C00..=0x0FFF => r1_bank + 1,
+                0x1000..=0x13FF => r2_bank,
+                0x1400..=0x17FF => r3_bank,
+                0x1800..=0x1BFF => r4_bank,
+                0x1C00..=0x1FFF => r5_bank,
                 _ => panic!("can't be"),
             },
             _ => match addr {
-                0x0000..=0x03FF => addr as usize + r2_bank * bank_len,
-                0x0400..=0x07FF => (addr - 0x0400) as usize + r3_bank * bank_len,
-                0x0800..=0x0BFF => (addr - 0x0800) as usize + r4_bank * bank_len,
-                0x0C00..=0x0FFF => (addr - 0x0C00) as usize + r5_bank * bank_len,
-                // 0x1000..=0x13FF
-                // 0x1400..=0x17FF
-                0x1000..=0x17FF => (addr - 0x1000) as usize + r0_bank * bank_len,
-                // 0x1800..=0x1BFF
-                // 0x1C00..=0x1FFF
-                0x1800..=0x1FFF => (addr - 0x1800) as usize + r1_bank * bank_len,
+                0x0000..=0x03FF => r2_bank,
+                0x0400..=0x07FF => r3_bank,
+                0x0800..=0x0BFF => r4_bank,
+                0x0C00..=0x0FFF => r5_bank,
+                0x1000..=0x13FF => r0_bank,
+                0x1400..=0x17FF => r0_bank + 1,
+                0x1800..=0x1BFF => r1_bank,
+                0x1C00..=0x1FFF => r1_bank + 1,
                 _ => panic!("can't be"),
             },
         }
     }
+
+    // TQROM: bit6が立っているバンクはCHR RAM
+    fn is_chr_ram_bank(&self, bank: usize) -> bool {
+        self.tqrom && bank & 0x40 != 0
+    }
+
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        let bank_len = 1 * 1024 as usize;
+        let bank = self.chr_bank(addr);
+        if self.is_chr_ram_bank(bank) {
+            let bank_max = self.chr_ram.len() / bank_len;
+            return (addr as usize & 0x03FF) + bank_len * (bank % bank_max);
+        }
+        let bank_max = self.rom.chr_rom.len() / bank_len;
+        (addr as usize & 0x03FF) + bank_len * (bank % bank_max)
+    }
+
+    // MMC6: $7000-$7FFF (1KBをミラー) の前半 $7000-$71FF と後半 $7200-$73FF で個別に保護する
+    //   書き込みには、書き込み許可と読み込み許可の両方が必要
+    fn mmc6_prg_ram_access(&self, addr: u16, write: bool) -> bool {
+        if self.bank_select & 0x20 == 0 || addr < 0x7000 {
+            return false;
+        }
+        let high = addr & 0x0200 != 0;
+        let (read_bit, write_bit) = if high { (0x80, 0x40) } else { (0x20, 0x10) };
+        let bits = if write {
+            read_bit | write_bit
+        } else {
+            read_bit
+        };
+        self.prg_ram_protect & bits == bits
+    }
 }
 
 impl Mapper for Mapper4 {
@@ -593,7 +635,7 @@ impl Mapper for Mapper4 {
         self.rom = rom;
     }
     fn is_chr_ram(&mut self) -> bool {
-        self.rom.is_chr_ram
+        self.tqrom || self.rom.is_chr_ram
     }
     fn write(&mut self, addr: u16, data: u8) {
         match addr {
@@ -612,7 +654,10 @@ impl Mapper for Mapper4 {
                     self.mirroring = data;
                 } else {
                     // PRG RAM 保護 ($A001-$BFFF、奇数)
-                    self.prg_ram_protect = data;
+                    // MMC6は$8000のbit5が立っている場合のみ書き込める
+                    if !self.mmc6 || self.bank_select & 0x20 != 0 {
+                        self.prg_ram_protect = data;
+                    }
                 }
             }
             0xC000..=0xDFFF => {
@@ -639,15 +684,36 @@ impl Mapper for Mapper4 {
         }
     }
     fn mirroring(&self) -> Mirroring {
+        // 4画面のボードはミラーリングレジスタを無視する
+        if self.rom.screen_mirroring == Mirroring::FOUR_SCREEN {
+            return Mirroring::FOUR_SCREEN;
+        }
         if self.mirroring & 0x01 == 0 {
             Mirroring::VERTICAL
         } else {
             Mirroring::HORIZONTAL
         }
     }
+    fn name_table(&self, name_table: usize) -> NameTable {
+        if self.txsrom {
+            // TxSROM: PPU $0000-$0FFFに割り当てたCHRバンクのbit7がCIRAM A10になる
+            let bank = self.chr_bank(name_table as u16 * 0x400);
+            return NameTable::Vram((bank >> 7) & 0x01);
+        }
+        NameTable::Vram(self.mirroring().vram_page(name_table))
+    }
 
     fn write_prg_ram(&mut self, addr: u16, data: u8) {
-        self.prg_ram[addr as usize - 0x6000] = data;
+        if self.mmc6 {
+            if !self.mmc6_prg_ram_access(addr, true) {
+                return;
+            }
+        } else if self.prg_ram_protect & 0xC0 != 0x80 {
+            // bit7: 有効, bit6: 書き込み禁止
+            return;
+        }
+        let mirror_addr = (addr as usize - 0x6000) % self.prg_ram.len();
+        self.prg_ram[mirror_addr] = data;
 
         // TODO 書き込み軽減措置を入れたほうが良い。
         // ex: 1秒間なにもなければ、実際に書き込む。
@@ -657,14 +723,26 @@ impl Mapper for Mapper4 {
     }
 
     fn read_prg_ram(&self, addr: u16) -> u8 {
-        self.prg_ram[addr as usize - 0x6000]
+        if self.mmc6 {
+            // 片方だけ読める場合、もう片方は0になる
+            if !self.mmc6_prg_ram_access(addr, false) {
+                return 0;
+            }
+        } else if self.prg_ram_protect & 0x80 == 0 {
+            return 0;
+        }
+        self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+    }
+
+    fn is_prg_ram_open_bus(&self, addr: u16) -> bool {
+        // MMC6: どちらも読めない場合は何も出力しない
+        self.mmc6
+            && (self.bank_select & 0x20 == 0 || addr < 0x7000 || self.prg_ram_protect & 0xA0 == 0)
     }
 
     fn load_prg_ram(&mut self, raw: &Vec<u8>) {
-        if raw.is_empty() {
-            return;
-        }
-        self.prg_ram = raw.to_vec()
+        let len = raw.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&raw[..len]);
     }
 
     fn read_prg_rom(&self, addr: u16) -> u8 {
@@ -675,71 +753,1330 @@ impl Mapper for Mapper4 {
 
         let last_bank = bank_max - 1;
         let last_bank2 = bank_max - 2;
-        let r6_bank = self.bank_data[6] as usize;
-        let r7_bank = self.bank_data[7] as usize;
+        let r6_bank = self.bank_data[6] as usize % bank_max;
+        let r7_bank = self.bank_data[7] as usize % bank_max;
 
-        match mode {
+        let bank = match mode {
+            // R6, R7, (-2), (-1)
             0 => match addr {
-                // R6, R7, (-2), (-1)
-                0x8000..=0x9FFF => {
-                    self.rom.prg_rom[((addr - 0x8000) as usize + r6_bank * bank_len) as usize]
-                }
-                0xA000..=0xBFFF => {
-                    self.rom.prg_rom[((addr - 0xA000) as usize + r7_bank * bank_len) as usize]
-                }
-                0xC000..=0xDFFF => {
-                    self.rom.prg_rom[((addr - 0xC000) as usize + last_bank2 * bank_len) as usize]
-                }
-                0xE000..=0xFFFF => {
-                    self.rom.prg_rom[((addr - 0xE000) as usize + last_bank * bank_len) as usize]
-                }
+                0x8000..=0x9FFF => r6_bank,
+                0xA000..=0xBFFF => r7_bank,
+                0xC000..=0xDFFF => last_bank2,
+                0xE000..=0xFFFF => last_bank,
                 _ => panic!("can't be"),
             },
+            // (-2), R7, R6, (-1)
             _ => match addr {
-                // (-2), R7, R6, (-1)
-                0x8000..=0x9FFF => {
-                    self.rom.prg_rom[((addr - 0x8000) as usize + last_bank2 * bank_len) as usize]
-                }
-                0xA000..=0xBFFF => {
-                    self.rom.prg_rom[((addr - 0xA000) as usize + r7_bank * bank_len) as usize]
-                }
-                0xC000..=0xDFFF => {
-                    self.rom.prg_rom[((addr - 0xC000) as usize + r6_bank * bank_len) as usize]
-                }
-                0xE000..=0xFFFF => {
-                    self.rom.prg_rom[((addr - 0xE000) as usize + last_bank * bank_len) as usize]
-                }
+                0x8000..=0x9FFF => last_bank2,
+                0xA000..=0xBFFF => r7_bank,
+                0xC000..=0xDFFF => r6_bank,
+                0xE000..=0xFFFF => last_bank,
                 _ => panic!("can't be"),
             },
-        }
+        };
+        self.rom.prg_rom[(addr as usize & 0x1FFF) + bank * bank_len]
     }
 
     fn write_chr_rom(&mut self, addr: u16, value: u8) {
         let mirror_addr = self.chr_rom_addr(addr);
-        self.rom.chr_rom[mirror_addr] = value;
+        if self.is_chr_ram_bank(self.chr_bank(addr)) {
+            self.chr_ram[mirror_addr] = value;
+        } else if self.rom.is_chr_ram {
+            self.rom.chr_rom[mirror_addr] = value;
+        }
     }
 
     fn read_chr_rom(&self, addr: u16) -> u8 {
+        if self.is_chr_ram_bank(self.chr_bank(addr)) {
+            return self.chr_ram[self.chr_rom_addr(addr)];
+        }
         self.rom.chr_rom[self.chr_rom_addr(addr)]
     }
 
-    fn scanline(&mut self, scanline: usize, show_background: bool) {
-        if scanline <= 240 && show_background {
-            if self.irq_counter == 0 || self.irq_reload {
-                self.irq_counter = self.irq_latch;
-                self.irq_reload = false;
-            } else {
-                self.irq_counter -= 1;
-            }
-            if self.irq_counter == 0 && self.irq_enable {
-                self.irq = true;
-            }
+    fn ppu_bus_address(&mut self, addr: u16, ppu_cycles: usize) {
+        if addr & 0x1000 == 0 {
+            return;
+        }
+        // A12が一定期間(M2で約3サイクル)Lowだった後の立ち上がりのみカウントする
+        if ppu_cycles.wrapping_sub(self.last_a12_high) >= 10 {
+            self.clock_irq_counter();
+        }
+        self.last_a12_high = ppu_cycles;
+    }
+
+    fn is_irq(&self) -> bool {
+        self.irq
+    }
+}
+
+// MMC2 (パンチアウト!!)
+// PPUが$0FD8/$0FE8, $1FD8-$1FDF/$1FE8-$1FEFを読み込むとラッチが切り替わり、CHRバンクが変わる。
+pub struct Mapper9 {
+    pub rom: Rom,
+    prg_bank: u8,
+    chr_bank0_fd: u8,
+    chr_bank0_fe: u8,
+    chr_bank1_fd: u8,
+    chr_bank1_fe: u8,
+    latch0: u8,
+    latch1: u8,
+    mirroring: u8,
+}
+
+impl Mapper9 {
+    pub fn new() -> Self {
+        Mapper9 {
+            rom: Rom::empty(),
+            prg_bank: 0,
+            chr_bank0_fd: 0,
+            chr_bank0_fe: 0,
+            chr_bank1_fd: 0,
+            chr_bank1_fe: 0,
+            latch0: 0xFE,
+            latch1: 0xFE,
+            mirroring: 0,
         }
     }
 
-    fn is_irq(&mut self) -> bool {
-        let res = self.irq;
-        self.irq = false;
-        res
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        chr_latch_addr(
+            &self.rom.chr_rom,
+            addr,
+            self.latch0,
+            self.latch1,
+            [
+                self.chr_bank0_fd,
+                self.chr_bank0_fe,
+                self.chr_bank1_fd,
+                self.chr_bank1_fe,
+            ],
+        )
+    }
+}
+
+impl Mapper for Mapper9 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0xF000 {
+            0xA000 => self.prg_bank = data & 0x0F,
+            0xB000 => self.chr_bank0_fd = data & 0x1F,
+            0xC000 => self.chr_bank0_fe = data & 0x1F,
+            0xD000 => self.chr_bank1_fd = data & 0x1F,
+            0xE000 => self.chr_bank1_fe = data & 0x1F,
+            0xF000 => self.mirroring = data & 0x01,
+            _ => {}
+        }
+    }
+    fn mirroring(&self) -> Mirroring {
+        if self.mirroring == 0 {
+            Mirroring::VERTICAL
+        } else {
+            Mirroring::HORIZONTAL
+        }
+    }
+
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {}
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        0
+    }
+    fn load_prg_ram(&mut self, raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        let bank_len = 8 * 1024 as usize;
+        let bank_max = self.rom.prg_rom.len() / bank_len;
+        match addr {
+            0x8000..=0x9FFF => {
+                let bank = self.prg_bank as usize % bank_max;
+                self.rom.prg_rom[addr as usize - 0x8000 + bank_len * bank]
+            }
+            // 最後の3バンク固定
+            0xA000..=0xFFFF => self.rom.prg_rom[addr as usize - 0xA000 + bank_len * (bank_max - 3)],
+            _ => panic!("can't be"),
+        }
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {}
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+    fn fetch_chr(&mut self, addr: u16) -> u8 {
+        let value = self.read_chr_rom(addr);
+        // ラッチは読み込みの後に切り替わる
+        match addr {
+            0x0FD8 => self.latch0 = 0xFD,
+            0x0FE8 => self.latch0 = 0xFE,
+            0x1FD8..=0x1FDF => self.latch1 = 0xFD,
+            0x1FE8..=0x1FEF => self.latch1 = 0xFE,
+            _ => {}
+        }
+        value
+    }
+    fn is_irq(&self) -> bool {
+        false
+    }
+}
+
+// MMC4 (ファイアーエムブレム)
+// MMC2とほぼ同じだが、PRGは16KB単位で、ラッチ0も8バイトの範囲で反応する。
+pub struct Mapper10 {
+    pub rom: Rom,
+    prg_ram: Vec<u8>,
+    prg_bank: u8,
+    chr_bank0_fd: u8,
+    chr_bank0_fe: u8,
+    chr_bank1_fd: u8,
+    chr_bank1_fe: u8,
+    latch0: u8,
+    latch1: u8,
+    mirroring: u8,
+}
+
+impl Mapper10 {
+    pub fn new() -> Self {
+        Mapper10 {
+            rom: Rom::empty(),
+            prg_ram: vec![0xFF; 8192],
+            prg_bank: 0,
+            chr_bank0_fd: 0,
+            chr_bank0_fe: 0,
+            chr_bank1_fd: 0,
+            chr_bank1_fe: 0,
+            latch0: 0xFE,
+            latch1: 0xFE,
+            mirroring: 0,
+        }
+    }
+
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        chr_latch_addr(
+            &self.rom.chr_rom,
+            addr,
+            self.latch0,
+            self.latch1,
+            [
+                self.chr_bank0_fd,
+                self.chr_bank0_fe,
+                self.chr_bank1_fd,
+                self.chr_bank1_fe,
+            ],
+        )
+    }
+}
+
+impl Mapper for Mapper10 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.load_prg_ram(&rom.save_data);
+        self.rom = rom;
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0xF000 {
+            0xA000 => self.prg_bank = data & 0x0F,
+            0xB000 => self.chr_bank0_fd = data & 0x1F,
+            0xC000 => self.chr_bank0_fe = data & 0x1F,
+            0xD000 => self.chr_bank1_fd = data & 0x1F,
+            0xE000 => self.chr_bank1_fe = data & 0x1F,
+            0xF000 => self.mirroring = data & 0x01,
+            _ => {}
+        }
+    }
+    fn mirroring(&self) -> Mirroring {
+        if self.mirroring == 0 {
+            Mirroring::VERTICAL
+        } else {
+            Mirroring::HORIZONTAL
+        }
+    }
+
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        self.prg_ram[addr as usize - 0x6000] = data;
+
+        if self.rom.has_battery {
+            let mut file = File::create(self.rom.save_data_file.as_str()).unwrap();
+            file.write_all(&self.prg_ram).unwrap();
+            file.flush().unwrap();
+        }
+    }
+
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        self.prg_ram[addr as usize - 0x6000]
+    }
+
+    fn load_prg_ram(&mut self, raw: &Vec<u8>) {
+        let len = raw.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&raw[..len]);
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        let bank_len = 16 * 1024 as usize;
+        let bank_max = self.rom.prg_rom.len() / bank_len;
+        match addr {
+            0x8000..=0xBFFF => {
+                let bank = self.prg_bank as usize % bank_max;
+                self.rom.prg_rom[addr as usize - 0x8000 + bank_len * bank]
+            }
+            // 最後のバンク固定
+            0xC000..=0xFFFF => self.rom.prg_rom[addr as usize - 0xC000 + bank_len * (bank_max - 1)],
+            _ => panic!("can't be"),
+        }
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {}
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+    fn fetch_chr(&mut self, addr: u16) -> u8 {
+        let value = self.read_chr_rom(addr);
+        // ラッチは読み込みの後に切り替わる
+        match addr {
+            0x0FD8..=0x0FDF => self.latch0 = 0xFD,
+            0x0FE8..=0x0FEF => self.latch0 = 0xFE,
+            0x1FD8..=0x1FDF => self.latch1 = 0xFD,
+            0x1FE8..=0x1FEF => self.latch1 = 0xFE,
+            _ => {}
+        }
+        value
+    }
+    fn is_irq(&self) -> bool {
+        false
+    }
+}
+
+// MMC2/MMC4共通: ラッチの状態から4KBのCHRバンクを選ぶ
+// banks = [$0000 FD, $0000 FE, $1000 FD, $1000 FE]
+fn chr_latch_addr(chr_rom: &Vec<u8>, addr: u16, latch0: u8, latch1: u8, banks: [u8; 4]) -> usize {
+    let bank_len = 4 * 1024 as usize;
+    let bank_max = chr_rom.len() / bank_len;
+    let bank = match (addr, latch0, latch1) {
+        (0x0000..=0x0FFF, 0xFD, _) => banks[0],
+        (0x0000..=0x0FFF, _, _) => banks[1],
+        (_, _, 0xFD) => banks[2],
+        (_, _, _) => banks[3],
+    };
+    (addr as usize & 0x0FFF) + bank_len * (bank as usize % bank_max)
+}
+
+// Konami VRC2/VRC4 (Mapper 21, 22, 23, 25)
+// ボードによってレジスタ選択に使うアドレス線が異なるため、submapperで判別する (vrc_submapper)。
+// どちらでもわからない場合は、両方のアドレス線のORで判定する。
+pub struct Vrc4 {
+    pub rom: Rom,
+    prg_ram: Vec<u8>,
+    vrc2: bool,
+    // レジスタ番号のbit0, bit1に対応するアドレス線
+    a0_mask: u16,
+    a1_mask: u16,
+    // VRC2aはCHRバンクの最下位ビットを無視する
+    chr_shift: u8,
+
+    prg_bank0: u8,
+    prg_bank1: u8,
+    prg_mode: u8,
+    mirroring: u8,
+    chr_banks: [u16; 8],
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_prescaler: i16,
+    irq_enable: bool,
+    irq_enable_after_ack: bool,
+    irq_cycle_mode: bool,
+    irq: bool,
+}
+
+// VRC2/VRC4の配線 (submapper) を決める
+//   1. ゲームのデータベース (CRC32) にあれば、それを使う
+//   2. なければ、ヘッダーのsubmapper (iNES 1.0 では0)
+fn vrc_submapper(rom: &Rom) -> u8 {
+    match gamedb::lookup(rom.crc32()) {
+        Some(game) if game.mapper == rom.mapper as u16 && game.submapper != 0 => {
+            if game.submapper != rom.submapper {
+                info!(
+                    "VRC2/VRC4 wiring from the game database: submapper {}",
+                    game.submapper
+                );
+            }
+            game.submapper
+        }
+        _ => rom.submapper,
+    }
+}
+
+impl Vrc4 {
+    pub fn new(mapper: u8, submapper: u8) -> Self {
+        let (vrc2, a0_mask, a1_mask) = match (mapper, submapper) {
+            (21, 1) => (false, 0x02, 0x04), // VRC4a: A1, A2
+            (21, 2) => (false, 0x40, 0x80), // VRC4c: A6, A7
+            (21, _) => (false, 0x42, 0x84),
+            (22, _) => (true, 0x02, 0x01),  // VRC2a: A1, A0
+            (23, 1) => (false, 0x01, 0x02), // VRC4f: A0, A1
+            (23, 2) => (false, 0x04, 0x08), // VRC4e: A2, A3
+            (23, 3) => (true, 0x01, 0x02),  // VRC2b: A0, A1
+            (23, _) => (false, 0x05, 0x0A),
+            (25, 1) => (false, 0x02, 0x01), // VRC4b: A1, A0
+            (25, 2) => (false, 0x08, 0x04), // VRC4d: A3, A2
+            (25, 3) => (true, 0x02, 0x01),  // VRC2c: A1, A0
+            (25, _) => (false, 0x0A, 0x05),
+            _ => panic!("not VRC2/VRC4 mapper. {}", mapper),
+        };
+        if mapper != 22 && submapper == 0 {
+            warn!(
+                "VRC2/VRC4 wiring is unknown (mapper {}), using both address lines",
+                mapper
+            );
+        }
+
+        Vrc4 {
+            rom: Rom::empty(),
+            prg_ram: vec![0xFF; 8192],
+            vrc2: vrc2,
+            a0_mask: a0_mask,
+            a1_mask: a1_mask,
+            chr_shift: if mapper == 22 { 1 } else { 0 },
+            prg_bank0: 0,
+            prg_bank1: 0,
+            prg_mode: 0,
+            mirroring: 0,
+            chr_banks: [0; 8],
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_prescaler: 341,
+            irq_enable: false,
+            irq_enable_after_ack: false,
+            irq_cycle_mode: false,
+            irq: false,
+        }
+    }
+
+    // ボードの配線に合わせて $x000-$x003 のレジスタ番号に変換する
+    fn register(&self, addr: u16) -> u16 {
+        let mut reg = addr & 0xF000;
+        if addr & self.a0_mask != 0 {
+            reg |= 0x01;
+        }
+        if addr & self.a1_mask != 0 {
+            reg |= 0x02;
+        }
+        reg
+    }
+
+    fn write_chr_bank(&mut self, reg: u16, data: u8) {
+        // $B000: CHR0 下位, $B001: CHR0 上位, $B002: CHR1 下位, $B003: CHR1 上位, ... $E003: CHR7 上位
+        let index = (((reg >> 12) - 0xB) * 2 + ((reg & 0x02) >> 1)) as usize;
+        let bank = self.chr_banks[index];
+        self.chr_banks[index] = if reg & 0x01 == 0 {
+            (bank & 0x1F0) | (data & 0x0F) as u16
+        } else {
+            let hi = if self.vrc2 { data & 0x0F } else { data & 0x1F };
+            (bank & 0x0F) | (hi as u16) << 4
+        };
+    }
+
+    fn chr_rom_addr(&self, addr: u16) -> usize {
+        let bank_len = 1 * 1024 as usize;
+        let bank_max = self.rom.chr_rom.len() / bank_len;
+        let bank = (self.chr_banks[(addr / 0x400) as usize] >> self.chr_shift) as usize;
+        (addr as usize & 0x03FF) + bank_len * (bank % bank_max)
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0xFF {
+            self.irq_counter = self.irq_latch;
+            self.irq = true;
+        } else {
+            self.irq_counter += 1;
+        }
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.load_prg_ram(&rom.save_data);
+        self.rom = rom;
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        let reg = self.register(addr);
+        match reg {
+            0x8000..=0x8003 => self.prg_bank0 = data & 0x1F,
+            0x9000 | 0x9001 => self.mirroring = if self.vrc2 { data & 0x01 } else { data & 0x03 },
+            0x9002 | 0x9003 => {
+                if !self.vrc2 {
+                    self.prg_mode = (data >> 1) & 0x01;
+                }
+            }
+            0xA000..=0xA003 => self.prg_bank1 = data & 0x1F,
+            0xB000..=0xEFFF => self.write_chr_bank(reg, data),
+            0xF000 => self.irq_latch = (self.irq_latch & 0xF0) | (data & 0x0F),
+            0xF001 => self.irq_latch = (self.irq_latch & 0x0F) | (data & 0x0F) << 4,
+            0xF002 => {
+                self.irq_enable_after_ack = data & 0x01 != 0;
+                self.irq_enable = data & 0x02 != 0;
+                self.irq_cycle_mode = data & 0x04 != 0;
+                self.irq = false;
+                if self.irq_enable {
+                    self.irq_counter = self.irq_latch;
+                    self.irq_prescaler = 341;
+                }
+            }
+            0xF003 => {
+                self.irq = false;
+                self.irq_enable = self.irq_enable_after_ack;
+            }
+            _ => {}
+        }
+    }
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::ONE_SCREEN_LOWER,
+            _ => Mirroring::ONE_SCREEN_UPPER,
+        }
+    }
+
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        self.prg_ram[addr as usize - 0x6000] = data;
+
+        if self.rom.has_battery {
+            let mut file = File::create(self.rom.save_data_file.as_str()).unwrap();
+            file.write_all(&self.prg_ram).unwrap();
+            file.flush().unwrap();
+        }
+    }
+
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        self.prg_ram[addr as usize - 0x6000]
+    }
+
+    fn load_prg_ram(&mut self, raw: &Vec<u8>) {
+        let len = raw.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&raw[..len]);
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        let bank_len = 8 * 1024 as usize;
+        let bank_max = self.rom.prg_rom.len() / bank_len;
+
+        let r0_bank = self.prg_bank0 as usize % bank_max;
+        let r1_bank = self.prg_bank1 as usize % bank_max;
+        let last_bank = bank_max - 1;
+        let last_bank2 = bank_max - 2;
+
+        let bank = match (self.prg_mode, addr) {
+            // R0, R1, (-2), (-1)
+            (0, 0x8000..=0x9FFF) => r0_bank,
+            (0, 0xC000..=0xDFFF) => last_bank2,
+            // (-2), R1, R0, (-1)
+            (_, 0x8000..=0x9FFF) => last_bank2,
+            (_, 0xC000..=0xDFFF) => r0_bank,
+            (_, 0xA000..=0xBFFF) => r1_bank,
+            (_, 0xE000..=0xFFFF) => last_bank,
+            _ => panic!("can't be"),
+        };
+        self.rom.prg_rom[(addr as usize & 0x1FFF) + bank_len * bank]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        let mirror_addr = self.chr_rom_addr(addr);
+        self.rom.chr_rom[mirror_addr] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[self.chr_rom_addr(addr)]
+    }
+    fn tick(&mut self, cycles: u8) {
+        if self.vrc2 || !self.irq_enable {
+            return;
+        }
+        for _ in 0..cycles {
+            if self.irq_cycle_mode {
+                self.clock_irq_counter();
+            } else {
+                // スキャンラインモード: 341/3 CPUサイクルごとにカウント
+                self.irq_prescaler -= 3;
+                if self.irq_prescaler <= 0 {
+                    self.irq_prescaler += 341;
+                    self.clock_irq_counter();
+                }
+            }
+        }
+    }
+    fn is_irq(&self) -> bool {
+        self.irq
+    }
+}
+
+// 32KB単位でPRG ROMを切り替えるディスクリートマッパー用
+fn prg_rom_32k(prg_rom: &Vec<u8>, bank: u8, addr: u16) -> u8 {
+    let bank_len = 32 * 1024 as usize;
+    let bank_max = (prg_rom.len() / bank_len).max(1);
+    let addr = (addr as usize - 0x8000) % prg_rom.len().min(bank_len);
+    prg_rom[addr + bank_len * (bank as usize % bank_max)]
+}
+
+// 8KB単位でCHRを切り替えるディスクリートマッパー用
+fn chr_rom_8k_addr(chr_rom: &Vec<u8>, bank: u8, addr: u16) -> usize {
+    let bank_len = 8 * 1024 as usize;
+    let bank_max = (chr_rom.len() / bank_len).max(1);
+    addr as usize + bank_len * (bank as usize % bank_max)
+}
+
+// AxROM (Mapper 7)
+pub struct Mapper7 {
+    pub rom: Rom,
+    bus_conflict: bool,
+    bank_select: u8,
+}
+
+impl Mapper7 {
+    pub fn new(submapper: u8) -> Self {
+        Mapper7 {
+            rom: Rom::empty(),
+            // submapper 2 (AMROM/AOROMの一部) のみバスコンフリクトあり
+            bus_conflict: submapper == 2,
+            bank_select: 0,
+        }
+    }
+}
+
+impl Mapper for Mapper7 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        let data = if self.bus_conflict {
+            data & self.read_prg_rom(addr)
+        } else {
+            data
+        };
+        self.bank_select = data;
+    }
+    fn mirroring(&self) -> Mirroring {
+        if self.bank_select & 0x10 == 0 {
+            Mirroring::ONE_SCREEN_LOWER
+        } else {
+            Mirroring::ONE_SCREEN_UPPER
+        }
+    }
+
+    fn write_prg_ram(&mut self, _addr: u16, _data: u8) {}
+    fn read_prg_ram(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        prg_rom_32k(&self.rom.prg_rom, self.bank_select & 0x07, addr)
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        self.rom.chr_rom[addr as usize] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[addr as usize]
+    }
+    fn is_irq(&self) -> bool {
+        false
+    }
+}
+
+// Color Dreams (Mapper 11)
+pub struct Mapper11 {
+    pub rom: Rom,
+    bank_select: u8,
+}
+
+impl Mapper11 {
+    pub fn new() -> Self {
+        Mapper11 {
+            rom: Rom::empty(),
+            bank_select: 0,
+        }
+    }
+}
+
+impl Mapper for Mapper11 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        // バスコンフリクトあり
+        self.bank_select = data & self.read_prg_rom(addr);
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.rom.screen_mirroring
+    }
+
+    fn write_prg_ram(&mut self, _addr: u16, _data: u8) {}
+    fn read_prg_ram(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        prg_rom_32k(&self.rom.prg_rom, self.bank_select & 0x03, addr)
+    }
+
+    fn write_chr_rom(&mut self, _addr: u16, _value: u8) {}
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[chr_rom_8k_addr(&self.rom.chr_rom, self.bank_select >> 4, addr)]
+    }
+    fn is_irq(&self) -> bool {
+        false
+    }
+}
+
+// BNROM / NINA-001 (Mapper 34)
+pub struct Mapper34 {
+    pub rom: Rom,
+    prg_ram: Vec<u8>,
+    nina001: bool,
+    prg_bank: u8,
+    chr_bank0: u8,
+    chr_bank1: u8,
+}
+
+impl Mapper34 {
+    pub fn new(submapper: u8, chr_rom_len: usize) -> Self {
+        let nina001 = match submapper {
+            1 => true,
+            2 => false,
+            // submapperが不明な場合、CHR ROMが8KBより大きければNINA-001
+            _ => chr_rom_len > 8 * 1024,
+        };
+        Mapper34 {
+            rom: Rom::empty(),
+            prg_ram: vec![0xFF; 8192],
+            nina001: nina001,
+            prg_bank: 0,
+            chr_bank0: 0,
+            chr_bank1: 1,
+        }
+    }
+}
+
+impl Mapper for Mapper34 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        if !self.nina001 {
+            // BNROM: バスコンフリクトあり
+            self.prg_bank = data & self.read_prg_rom(addr);
+        }
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.rom.screen_mirroring
+    }
+
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        if self.nina001 {
+            // NINA-001: レジスタへの書き込みはPRG RAMにも書き込まれる
+            match addr {
+                0x7FFD => self.prg_bank = data & 0x01,
+                0x7FFE => self.chr_bank0 = data & 0x0F,
+                0x7FFF => self.chr_bank1 = data & 0x0F,
+                _ => {}
+            }
+        }
+        self.prg_ram[addr as usize - 0x6000] = data;
+    }
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        self.prg_ram[addr as usize - 0x6000]
+    }
+    fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        prg_rom_32k(&self.rom.prg_rom, self.prg_bank, addr)
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        self.rom.chr_rom[addr as usize] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        if !self.nina001 {
+            return self.rom.chr_rom[addr as usize];
+        }
+        let bank_len = 4 * 1024 as usize;
+        let bank_max = self.rom.chr_rom.len() / bank_len;
+        let bank = match addr {
+            0x0000..=0x0FFF => self.chr_bank0,
+            _ => self.chr_bank1,
+        };
+        self.rom.chr_rom[(addr as usize & 0x0FFF) + bank_len * (bank as usize % bank_max)]
+    }
+    fn is_irq(&self) -> bool {
+        false
+    }
+}
+
+// GxROM (Mapper 66)
+pub struct Mapper66 {
+    pub rom: Rom,
+    bank_select: u8,
+}
+
+impl Mapper66 {
+    pub fn new() -> Self {
+        Mapper66 {
+            rom: Rom::empty(),
+            bank_select: 0,
+        }
+    }
+}
+
+impl Mapper for Mapper66 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        // バスコンフリクトあり
+        self.bank_select = data & self.read_prg_rom(addr);
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.rom.screen_mirroring
+    }
+
+    fn write_prg_ram(&mut self, _addr: u16, _data: u8) {}
+    fn read_prg_ram(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        prg_rom_32k(&self.rom.prg_rom, (self.bank_select >> 4) & 0x03, addr)
+    }
+
+    fn write_chr_rom(&mut self, _addr: u16, _value: u8) {}
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[chr_rom_8k_addr(&self.rom.chr_rom, self.bank_select & 0x03, addr)]
+    }
+    fn is_irq(&self) -> bool {
+        false
+    }
+}
+
+// Sunsoft-4 (Mapper 68)
+// ネームテーブルにCHR ROMの1KBバンクを割り当てられる (バンクのbit7は常に1)
+// see: https://www.nesdev.org/wiki/INES_Mapper_068
+pub struct Mapper68 {
+    pub rom: Rom,
+    prg_ram: Vec<u8>,
+    chr_banks: [u8; 4],
+    name_table_banks: [u8; 2],
+    // bit0-1: ミラーリング, bit4: ネームテーブルにCHR ROMを使う
+    control: u8,
+    // bit0-3: PRGバンク, bit4: PRG RAM有効
+    prg_bank: u8,
+}
+
+impl Mapper68 {
+    pub fn new() -> Self {
+        Mapper68 {
+            rom: Rom::empty(),
+            prg_ram: vec![0xFF; 8192],
+            chr_banks: [0; 4],
+            name_table_banks: [0; 2],
+            control: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 != 0
+    }
+}
+
+impl Mapper for Mapper68 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.load_prg_ram(&rom.save_data);
+        self.rom = rom;
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0xF000 {
+            // 2KBのCHRバンク ($0000, $0800, $1000, $1800)
+            0x8000 => self.chr_banks[0] = data,
+            0x9000 => self.chr_banks[1] = data,
+            0xA000 => self.chr_banks[2] = data,
+            0xB000 => self.chr_banks[3] = data,
+            // ネームテーブルに使うCHR ROMの1KBバンク
+            0xC000 => self.name_table_banks[0] = data | 0x80,
+            0xD000 => self.name_table_banks[1] = data | 0x80,
+            0xE000 => self.control = data,
+            0xF000 => self.prg_bank = data,
+            _ => {}
+        }
+    }
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::ONE_SCREEN_LOWER,
+            _ => Mirroring::ONE_SCREEN_UPPER,
+        }
+    }
+    fn name_table(&self, name_table: usize) -> NameTable {
+        // ミラーリングで選ばれたページの代わりに、同じ番号のレジスタのバンクを使う
+        let page = self.mirroring().vram_page(name_table);
+        if self.control & 0x10 != 0 {
+            NameTable::ChrRom(self.name_table_banks[page] as usize)
+        } else {
+            NameTable::Vram(page)
+        }
+    }
+    fn read_chr_name_table(&self, bank: usize, addr: u16) -> u8 {
+        let bank_len = 1024 as usize;
+        let bank_max = self.rom.chr_rom.len() / bank_len;
+        self.rom.chr_rom[(addr as usize & 0x03FF) + bank_len * (bank % bank_max)]
+    }
+
+    fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        if !self.prg_ram_enabled() {
+            return;
+        }
+        self.prg_ram[addr as usize - 0x6000] = data;
+
+        if self.rom.has_battery {
+            let mut file = File::create(self.rom.save_data_file.as_str()).unwrap();
+            file.write_all(&self.prg_ram).unwrap();
+            file.flush().unwrap();
+        }
+    }
+    fn read_prg_ram(&self, addr: u16) -> u8 {
+        self.prg_ram[addr as usize - 0x6000]
+    }
+    fn is_prg_ram_open_bus(&self, _addr: u16) -> bool {
+        !self.prg_ram_enabled()
+    }
+    fn load_prg_ram(&mut self, raw: &Vec<u8>) {
+        let len = raw.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&raw[..len]);
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        let bank_len = 16 * 1024 as usize;
+        let bank_max = self.rom.prg_rom.len() / bank_len;
+        match addr {
+            0x8000..=0xBFFF => {
+                let bank = (self.prg_bank & 0x0F) as usize % bank_max;
+                self.rom.prg_rom[addr as usize - 0x8000 + bank_len * bank]
+            }
+            // 最後のバンク固定
+            0xC000..=0xFFFF => self.rom.prg_rom[addr as usize - 0xC000 + bank_len * (bank_max - 1)],
+            _ => panic!("can't be"),
+        }
+    }
+
+    fn write_chr_rom(&mut self, _addr: u16, _value: u8) {}
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        let bank_len = 2 * 1024 as usize;
+        let bank_max = self.rom.chr_rom.len() / bank_len;
+        let bank = self.chr_banks[addr as usize / bank_len] as usize % bank_max;
+        self.rom.chr_rom[(addr as usize % bank_len) + bank_len * bank]
+    }
+    fn is_irq(&self) -> bool {
+        false
+    }
+}
+
+// Camerica BF9093/BF9097 (Mapper 71)
+pub struct Mapper71 {
+    pub rom: Rom,
+    bank_select: u8,
+    // Fire Hawk (BF9097) はone-screenのミラーリングを切り替える
+    one_screen: bool,
+    mirroring: u8,
+}
+
+impl Mapper71 {
+    pub fn new(submapper: u8) -> Self {
+        Mapper71 {
+            rom: Rom::empty(),
+            bank_select: 0,
+            one_screen: submapper == 1,
+            mirroring: 0,
+        }
+    }
+}
+
+impl Mapper for Mapper71 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0x9FFF => {
+                // submapperが不明な場合、$9000への書き込みがあればBF9097とみなす
+                if addr >= 0x9000 {
+                    self.one_screen = true;
+                }
+                self.mirroring = (data >> 4) & 0x01;
+            }
+            0xC000..=0xFFFF => self.bank_select = data,
+            _ => {}
+        }
+    }
+    fn mirroring(&self) -> Mirroring {
+        if !self.one_screen {
+            return self.rom.screen_mirroring;
+        }
+        if self.mirroring == 0 {
+            Mirroring::ONE_SCREEN_LOWER
+        } else {
+            Mirroring::ONE_SCREEN_UPPER
+        }
+    }
+
+    fn write_prg_ram(&mut self, _addr: u16, _data: u8) {}
+    fn read_prg_ram(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        let bank_len = 16 * 1024 as usize;
+        let bank_max = self.rom.prg_rom.len() / bank_len;
+        match addr {
+            0x8000..=0xBFFF => {
+                let bank = self.bank_select as usize % bank_max;
+                self.rom.prg_rom[addr as usize - 0x8000 + bank_len * bank]
+            }
+            // 最後のバンク固定
+            0xC000..=0xFFFF => self.rom.prg_rom[addr as usize - 0xC000 + bank_len * (bank_max - 1)],
+            _ => panic!("can't be"),
+        }
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, value: u8) {
+        self.rom.chr_rom[addr as usize] = value;
+    }
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[addr as usize]
+    }
+    fn is_irq(&self) -> bool {
+        false
+    }
+}
+
+// NINA-03/NINA-06 (Mapper 79)
+pub struct Mapper79 {
+    pub rom: Rom,
+    bank_select: u8,
+}
+
+impl Mapper79 {
+    pub fn new() -> Self {
+        Mapper79 {
+            rom: Rom::empty(),
+            bank_select: 0,
+        }
+    }
+}
+
+impl Mapper for Mapper79 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, _addr: u16, _data: u8) {}
+    fn write_expansion(&mut self, addr: u16, data: u8) {
+        // $4100-$5FFF (A8=1) のみ反応する
+        if addr & 0xE100 == 0x4100 {
+            self.bank_select = data;
+        }
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.rom.screen_mirroring
+    }
+
+    fn write_prg_ram(&mut self, _addr: u16, _data: u8) {}
+    fn read_prg_ram(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        prg_rom_32k(&self.rom.prg_rom, (self.bank_select >> 3) & 0x01, addr)
+    }
+
+    fn write_chr_rom(&mut self, _addr: u16, _value: u8) {}
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[chr_rom_8k_addr(&self.rom.chr_rom, self.bank_select & 0x07, addr)]
+    }
+    fn is_irq(&self) -> bool {
+        false
+    }
+}
+
+// Jaleco JF-05~JF-10 ほか (Mapper 87)
+pub struct Mapper87 {
+    pub rom: Rom,
+    bank_select: u8,
+}
+
+impl Mapper87 {
+    pub fn new() -> Self {
+        Mapper87 {
+            rom: Rom::empty(),
+            bank_select: 0,
+        }
+    }
+}
+
+impl Mapper for Mapper87 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, _addr: u16, _data: u8) {}
+    fn mirroring(&self) -> Mirroring {
+        self.rom.screen_mirroring
+    }
+
+    fn write_prg_ram(&mut self, _addr: u16, data: u8) {
+        // $6000-$7FFF への書き込みでCHRバンクを選択する。bit0とbit1が入れ替わっている。
+        self.bank_select = ((data & 0x01) << 1) | ((data & 0x02) >> 1);
+    }
+    fn read_prg_ram(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        prg_rom_32k(&self.rom.prg_rom, 0, addr)
+    }
+
+    fn write_chr_rom(&mut self, _addr: u16, _value: u8) {}
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[chr_rom_8k_addr(&self.rom.chr_rom, self.bank_select, addr)]
+    }
+    fn is_irq(&self) -> bool {
+        false
+    }
+}
+
+// Jaleco JF-11/JF-14 (Mapper 140)
+pub struct Mapper140 {
+    pub rom: Rom,
+    bank_select: u8,
+}
+
+impl Mapper140 {
+    pub fn new() -> Self {
+        Mapper140 {
+            rom: Rom::empty(),
+            bank_select: 0,
+        }
+    }
+}
+
+impl Mapper for Mapper140 {
+    fn set_rom(&mut self, rom: Rom) {
+        self.rom = rom
+    }
+    fn is_chr_ram(&mut self) -> bool {
+        self.rom.is_chr_ram
+    }
+    fn write(&mut self, _addr: u16, _data: u8) {}
+    fn mirroring(&self) -> Mirroring {
+        self.rom.screen_mirroring
+    }
+
+    fn write_prg_ram(&mut self, _addr: u16, data: u8) {
+        // $6000-$7FFF への書き込みでバンクを選択する
+        self.bank_select = data;
+    }
+    fn read_prg_ram(&self, _addr: u16) -> u8 {
+        0
+    }
+    fn load_prg_ram(&mut self, _raw: &Vec<u8>) {}
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        prg_rom_32k(&self.rom.prg_rom, (self.bank_select >> 4) & 0x03, addr)
+    }
+
+    fn write_chr_rom(&mut self, _addr: u16, _value: u8) {}
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.rom.chr_rom[chr_rom_8k_addr(&self.rom.chr_rom, self.bank_select & 0x0F, addr)]
+    }
+    fn is_irq(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::MAPPER;
+    use std::sync::{Mutex, MutexGuard};
+
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    // MAPPERはグローバルなので、差し替えるテストはガードを持っている間1つずつ走らせる
+    pub fn insert_mapper(rom: Rom) -> MutexGuard<'static, ()> {
+        let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe { *MAPPER = create_mapper(rom) };
+        guard
+    }
+
+    // バンクごとに、中身がバンク番号になっているCHR ROM
+    fn banked_chr_rom(mapper: u8, bank_len: usize, banks: usize) -> Rom {
+        let mut rom = Rom::empty();
+        rom.mapper = mapper;
+        rom.prg_rom = vec![0; 128 * 1024];
+        rom.chr_rom = (0..bank_len * banks)
+            .map(|i| (i / bank_len) as u8)
+            .collect();
+        rom
+    }
+
+    #[test]
+    fn test_mmc2_mmc4_latch() {
+        for mapper in [9, 10] {
+            let mut m = create_mapper(banked_chr_rom(mapper, 4 * 1024, 4));
+            m.write(0xB000, 1); // $0000 FD
+            m.write(0xC000, 2); // $0000 FE
+            m.write(0xD000, 3); // $1000 FD
+            m.write(0xE000, 0); // $1000 FE
+
+            // 電源投入時はFE
+            assert_eq!(m.fetch_chr(0x0000), 2);
+            assert_eq!(m.fetch_chr(0x1000), 0);
+
+            // $0FD8 を読んだ後で切り替わる
+            assert_eq!(m.fetch_chr(0x0FD8), 2);
+            assert_eq!(m.fetch_chr(0x0000), 1);
+            assert_eq!(m.fetch_chr(0x0FE8), 1);
+            assert_eq!(m.fetch_chr(0x0000), 2);
+
+            // $1000 側は $1FD8-$1FDF, $1FE8-$1FEF
+            m.fetch_chr(0x1FDA);
+            assert_eq!(m.fetch_chr(0x1000), 3);
+            m.fetch_chr(0x1FEF);
+            assert_eq!(m.fetch_chr(0x1000), 0);
+
+            // $0000 側が8バイトの範囲で反応するのはMMC4だけ
+            m.fetch_chr(0x0FDA);
+            let expected = if mapper == 10 { 1 } else { 2 };
+            assert_eq!(m.fetch_chr(0x0000), expected);
+
+            // CPUからの読み込みではラッチは変わらない
+            m.fetch_chr(0x0FE8);
+            m.read_chr_rom(0x0FD8);
+            assert_eq!(m.fetch_chr(0x0000), 2);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_snrom_needs_prg_ram() {
+        let mut rom = Rom::empty();
+        rom.mapper = 1;
+        rom.prg_rom = vec![0; 32 * 1024];
+        rom.chr_rom = vec![0; 8 * 1024];
+        rom.is_chr_ram = true;
+        rom.prg_ram_size = 8 * 1024;
+
+        // iNES 1.0 のサイズは推測なので、SGROM (PRG RAMなし) かもしれない
+        assert_eq!(Mapper1::detect_board(&rom), Mmc1Board::Standard);
+        rom.has_battery = true;
+        assert_eq!(Mapper1::detect_board(&rom), Mmc1Board::Snrom);
+
+        rom.has_battery = false;
+        rom.prg_ram_size_known = true;
+        assert_eq!(Mapper1::detect_board(&rom), Mmc1Board::Snrom);
+        rom.prg_ram_size = 0;
+        assert_eq!(Mapper1::detect_board(&rom), Mmc1Board::Standard);
+    }
+
+    #[test]
+    fn test_vrc_address_lines() {
+        // (mapper, submapper, レジスタ番号のbit0のアドレス線, bit1のアドレス線)
+        let boards = [
+            (21, 1, 0x02, 0x04), // VRC4a
+            (21, 2, 0x40, 0x80), // VRC4c
+            (22, 0, 0x02, 0x01), // VRC2a
+            (23, 1, 0x01, 0x02), // VRC4f
+            (23, 2, 0x04, 0x08), // VRC4e
+            (23, 3, 0x01, 0x02), // VRC2b
+            (25, 1, 0x02, 0x01), // VRC4b
+            (25, 2, 0x08, 0x04), // VRC4d
+            (25, 3, 0x02, 0x01), // VRC2c
+            // submapperが不明なら、どちらの配線でも動く
+            (21, 0, 0x02, 0x04),
+            (21, 0, 0x40, 0x80),
+            (23, 0, 0x01, 0x02),
+            (23, 0, 0x04, 0x08),
+            (25, 0, 0x02, 0x01),
+            (25, 0, 0x08, 0x04),
+        ];
+        for (mapper, submapper, a0, a1) in boards {
+            let mut rom = banked_chr_rom(mapper, 1024, 32);
+            rom.submapper = submapper;
+            let mut m = create_mapper(rom);
+            // $B000: CHR0 下位, $B001: CHR0 上位, $B002: CHR1 下位
+            m.write(0xB000, 0x04);
+            m.write(0xB000 | a0, 0x01);
+            m.write(0xB000 | a1, 0x06);
+            // VRC2aはCHRバンクの最下位ビットを無視する
+            let shift = if mapper == 22 { 1 } else { 0 };
+            let board = format!("mapper {} submapper {}", mapper, submapper);
+            assert_eq!(m.read_chr_rom(0x0000), 0x14 >> shift, "{}", board);
+            assert_eq!(m.read_chr_rom(0x0400), 0x06 >> shift, "{}", board);
+        }
+    }
+
+    #[test]
+    fn test_mmc3_a12_filter() {
+        let mut m = create_mapper(banked_chr_rom(4, 1024, 8));
+        m.write(0xC000, 2); // IRQ ラッチ
+        m.write(0xC001, 0); // IRQ リロード
+        m.write(0xE001, 0); // IRQ イネーブル
+
+        // 背景が$0000、スプライトが$1000のとき、A12はスプライトのフェッチ (ドット257-320) で立つ。
+        // 8個のスプライトのフェッチで何度も立つが、間隔が短いので1ラインに1回しか数えない
+        fn scanline(m: &mut Box<dyn Mapper>, line: usize) {
+            for i in 0..8 {
+                let dot = line * 341 + 257 + i * 8;
+                m.ppu_bus_address(0x0000, dot);
+                m.ppu_bus_address(0x1000, dot + 4);
+                m.ppu_bus_address(0x1008, dot + 6);
+            }
+        }
+        scanline(&mut m, 1); // リロード => 2
+        scanline(&mut m, 2); // 1
+        assert!(!m.is_irq());
+        scanline(&mut m, 3); // 0 => IRQ
+        assert!(m.is_irq());
     }
 }
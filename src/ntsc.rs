@@ -0,0 +1,136 @@
+use crate::frame::Frame;
+use crate::palette::{ntsc_carrier, ntsc_signal, yiq_to_rgb, NtscParams};
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+// 1ドットは8マスタークロック。色信号は12マスタークロックで1周期。
+const SAMPLES_PER_DOT: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NtscPreset {
+    Composite,
+    SVideo,
+    Rgb,
+}
+
+impl NtscPreset {
+    pub fn parse(name: &str) -> Option<NtscPreset> {
+        match name {
+            "composite" => Some(NtscPreset::Composite),
+            "svideo" => Some(NtscPreset::SVideo),
+            "rgb" => Some(NtscPreset::Rgb),
+            _ => None,
+        }
+    }
+
+    pub fn next(&self) -> NtscPreset {
+        match self {
+            NtscPreset::Composite => NtscPreset::SVideo,
+            NtscPreset::SVideo => NtscPreset::Rgb,
+            NtscPreset::Rgb => NtscPreset::Composite,
+        }
+    }
+
+    // (輝度, 色差) を平均するサンプル数
+    fn windows(&self) -> (usize, usize) {
+        match self {
+            // 輝度と色が分離しきれないので、ドットクロールや色にじみが出る
+            NtscPreset::Composite => (6, 24),
+            NtscPreset::SVideo => (12, 24),
+            NtscPreset::Rgb => (1, 1),
+        }
+    }
+}
+
+// PPUの出力(パレット番号)をNTSCの信号に変換し、デコードし直すフィルター
+// see: https://www.nesdev.org/wiki/NTSC_video
+pub struct NtscFilter {
+    pub preset: NtscPreset,
+    params: NtscParams,
+    frame_count: usize,
+    carrier: Vec<(f32, f32)>,
+    signal: Vec<f32>,
+    luma: Vec<f32>,
+    pub data: Vec<u8>,
+}
+
+impl NtscFilter {
+    pub fn new(preset: NtscPreset) -> Self {
+        NtscFilter {
+            preset: preset,
+            params: NtscParams::new(),
+            frame_count: 0,
+            carrier: (0..12).map(|phase| ntsc_carrier(phase)).collect(),
+            signal: vec![0.0; WIDTH * SAMPLES_PER_DOT],
+            luma: vec![0.0; WIDTH * SAMPLES_PER_DOT],
+            data: vec![0; WIDTH * HEIGHT * 3],
+        }
+    }
+
+    // RGB24 (256x240) に変換した結果をdataに入れる
+    pub fn apply(&mut self, frame: &Frame) {
+        if self.preset == NtscPreset::Rgb {
+            self.data.copy_from_slice(&frame.data);
+            return;
+        }
+
+        // 1ラインは341ドット (341 * 8 % 12 = 4) なので、ラインごとに位相が4ずつずれる。
+        // フレームごとにもずれるので、ドットクロールになる。
+        self.frame_count += 1;
+        let frame_phase = (self.frame_count % 3) * 4;
+        let (luma_window, chroma_window) = self.preset.windows();
+
+        for y in 0..HEIGHT {
+            let line_phase = frame_phase + y * 4;
+            for x in 0..WIDTH {
+                let pixel = frame.index(x, y);
+                let mut luma = 0.0;
+                for k in 0..SAMPLES_PER_DOT {
+                    let phase = (line_phase + x * SAMPLES_PER_DOT + k) % 12;
+                    let s = ntsc_signal(pixel, phase);
+                    self.signal[x * SAMPLES_PER_DOT + k] = s;
+                    luma += s;
+                }
+                // S-Videoでは輝度を別の信号で送る
+                for k in 0..SAMPLES_PER_DOT {
+                    self.luma[x * SAMPLES_PER_DOT + k] = luma / SAMPLES_PER_DOT as f32;
+                }
+            }
+
+            for x in 0..WIDTH {
+                let center = x * SAMPLES_PER_DOT + SAMPLES_PER_DOT / 2;
+                let luma_source = if self.preset == NtscPreset::SVideo {
+                    &self.luma
+                } else {
+                    &self.signal
+                };
+                let yy = average(luma_source, center, luma_window, |_| 1.0);
+                let carrier = &self.carrier;
+                let i = average(&self.signal, center, chroma_window, |n| {
+                    carrier[(line_phase + n) % 12].0
+                }) * 2.0;
+                let q = average(&self.signal, center, chroma_window, |n| {
+                    carrier[(line_phase + n) % 12].1
+                }) * 2.0;
+
+                let (r, g, b) = yiq_to_rgb(yy, i, q, &self.params);
+                let base = (y * WIDTH + x) * 3;
+                self.data[base] = r;
+                self.data[base + 1] = g;
+                self.data[base + 2] = b;
+            }
+        }
+    }
+}
+
+// centerを中心に、window個のサンプルに重みをかけて平均する
+fn average(samples: &Vec<f32>, center: usize, window: usize, weight: impl Fn(usize) -> f32) -> f32 {
+    let start = center as isize - (window / 2) as isize;
+    let mut sum = 0.0;
+    for n in start..start + window as isize {
+        // 画面の端は、端のサンプルを繰り返す
+        let n = n.max(0).min(samples.len() as isize - 1) as usize;
+        sum += samples[n] * weight(n);
+    }
+    sum / window as f32
+}
@@ -1,6 +1,6 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::collections::HashMap;
 use once_cell::sync::Lazy;
-use crate::cpu::{AddressingMode, CycleCalcMode, OpCode, CPU};
+use crate::cpu::{AddressingMode, CycleCalcMode, Instruction, OpCode, CPU};
 
 pub static CPU_OPS_CODES: Lazy<HashMap<u8, OpCode>> = Lazy::new(|| {
   let mut m = HashMap::new();
@@ -265,392 +265,95 @@ pub static CPU_OPS_CODES: Lazy<HashMap<u8, OpCode>> = Lazy::new(|| {
   m
 });
 
+// オペコードで直接引けるテーブル (実行中にHashMapを引かないように)
+pub static CPU_OPS_TABLE: Lazy<Vec<Option<&'static OpCode>>> =
+  Lazy::new(|| (0..=0xFF).map(|code: u8| CPU_OPS_CODES.get(&code)).collect());
+
+
+// 命令名から実装を引く。テーブルの作成時に一度だけ呼ばれる。
+pub fn instruction(name: &str) -> Instruction {
+  match name.trim_start_matches('*') {
+    "ADC" => |cpu, mode| cpu.adc(mode),
+    "AND" => |cpu, mode| cpu.and(mode),
+    "ASL" => |cpu, mode| cpu.asl(mode),
+    "BCC" => |cpu, mode| cpu.bcc(mode),
+    "BCS" => |cpu, mode| cpu.bcs(mode),
+    "BEQ" => |cpu, mode| cpu.beq(mode),
+    "BIT" => |cpu, mode| cpu.bit(mode),
+    "BMI" => |cpu, mode| cpu.bmi(mode),
+    "BNE" => |cpu, mode| cpu.bne(mode),
+    "BPL" => |cpu, mode| cpu.bpl(mode),
+    "BRK" => |cpu, mode| cpu.brk(mode),
+    "BVC" => |cpu, mode| cpu.bvc(mode),
+    "BVS" => |cpu, mode| cpu.bvs(mode),
+    "CLC" => |cpu, mode| cpu.clc(mode),
+    "CLD" => |cpu, mode| cpu.cld(mode),
+    "CLI" => |cpu, mode| cpu.cli(mode),
+    "CLV" => |cpu, mode| cpu.clv(mode),
+    "CMP" => |cpu, mode| cpu.cmp(mode),
+    "CPX" => |cpu, mode| cpu.cpx(mode),
+    "CPY" => |cpu, mode| cpu.cpy(mode),
+    "DEC" => |cpu, mode| cpu.dec(mode),
+    "DEX" => |cpu, mode| cpu.dex(mode),
+    "DEY" => |cpu, mode| cpu.dey(mode),
+    "EOR" => |cpu, mode| cpu.eor(mode),
+    "INC" => |cpu, mode| cpu.inc(mode),
+    "INX" => |cpu, mode| cpu.inx(mode),
+    "INY" => |cpu, mode| cpu.iny(mode),
+    "JMP" => |cpu, mode| cpu.jmp(mode),
+    "JSR" => |cpu, mode| cpu.jsr(mode),
+    "LDA" => |cpu, mode| cpu.lda(mode),
+    "LDX" => |cpu, mode| cpu.ldx(mode),
+    "LDY" => |cpu, mode| cpu.ldy(mode),
+    "LSR" => |cpu, mode| cpu.lsr(mode),
+    "NOP" => |cpu, mode| cpu.nop(mode),
+    "ORA" => |cpu, mode| cpu.ora(mode),
+    "PHA" => |cpu, mode| cpu.pha(mode),
+    "PHP" => |cpu, mode| cpu.php(mode),
+    "PLA" => |cpu, mode| cpu.pla(mode),
+    "PLP" => |cpu, mode| cpu.plp(mode),
+    "ROL" => |cpu, mode| cpu.rol(mode),
+    "ROR" => |cpu, mode| cpu.ror(mode),
+    "RTI" => |cpu, mode| cpu.rti(mode),
+    "RTS" => |cpu, mode| cpu.rts(mode),
+    "SBC" => |cpu, mode| cpu.sbc(mode),
+    "SEC" => |cpu, mode| cpu.sec(mode),
+    "SED" => |cpu, mode| cpu.sed(mode),
+    "SEI" => |cpu, mode| cpu.sei(mode),
+    "STA" => |cpu, mode| cpu.sta(mode),
+    "STX" => |cpu, mode| cpu.stx(mode),
+    "STY" => |cpu, mode| cpu.sty(mode),
+    "TAX" => |cpu, mode| cpu.tax(mode),
+    "TAY" => |cpu, mode| cpu.tay(mode),
+    "TSX" => |cpu, mode| cpu.tsx(mode),
+    "TXA" => |cpu, mode| cpu.txa(mode),
+    "TXS" => |cpu, mode| cpu.txs(mode),
+    "TYA" => |cpu, mode| cpu.tya(mode),
+    "ANC" => |cpu, mode| cpu.anc(mode),
+    "SAX" => |cpu, mode| cpu.sax(mode),
+    "ARR" => |cpu, mode| cpu.arr(mode),
+    "ASR" => |cpu, mode| cpu.asr(mode),
+    "LXA" => |cpu, mode| cpu.lxa(mode),
+    "SHA" => |cpu, mode| cpu.sha(mode),
+    "SBX" => |cpu, mode| cpu.sbx(mode),
+    "DCP" => |cpu, mode| cpu.dcp(mode),
+    "ISB" => |cpu, mode| cpu.isb(mode),
+    "JAM" => |cpu, mode| cpu.jam(mode),
+    "LAE" => |cpu, mode| cpu.lae(mode),
+    "LAX" => |cpu, mode| cpu.lax(mode),
+    "RLA" => |cpu, mode| cpu.rla(mode),
+    "RRA" => |cpu, mode| cpu.rra(mode),
+    "SLO" => |cpu, mode| cpu.slo(mode),
+    "SRE" => |cpu, mode| cpu.sre(mode),
+    "SHX" => |cpu, mode| cpu.shx(mode),
+    "SHY" => |cpu, mode| cpu.shy(mode),
+    "ANE" => |cpu, mode| cpu.ane(mode),
+    "SHS" => |cpu, mode| cpu.shs(mode),
+    _ => panic!("no implementation {}", name),
+  }
+}
 
 pub fn call(cpu: &mut CPU, op: &OpCode) {
-  match op.name.replace("*", "").as_str() {
-
-    "ADC" => {
-      cpu.adc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "AND" => {
-      cpu.and(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "ASL" => {
-      cpu.asl(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "BCC" => {
-      cpu.bcc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "BCS" => {
-      cpu.bcs(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "BEQ" => {
-      cpu.beq(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "BIT" => {
-      cpu.bit(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "BMI" => {
-      cpu.bmi(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "BNE" => {
-      cpu.bne(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "BPL" => {
-      cpu.bpl(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "BRK" => {
-      cpu.brk(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "BVC" => {
-      cpu.bvc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "BVS" => {
-      cpu.bvs(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "CLC" => {
-      cpu.clc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "CLD" => {
-      cpu.cld(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "CLI" => {
-      cpu.cli(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "CLV" => {
-      cpu.clv(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "CMP" => {
-      cpu.cmp(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "CPX" => {
-      cpu.cpx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "CPY" => {
-      cpu.cpy(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "DEC" => {
-      cpu.dec(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "DEX" => {
-      cpu.dex(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "DEY" => {
-      cpu.dey(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "EOR" => {
-      cpu.eor(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "INC" => {
-      cpu.inc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "INX" => {
-      cpu.inx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "INY" => {
-      cpu.iny(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "JMP" => {
-      cpu.jmp(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "JSR" => {
-      cpu.jsr(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "LDA" => {
-      cpu.lda(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "LDX" => {
-      cpu.ldx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "LDY" => {
-      cpu.ldy(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "LSR" => {
-      cpu.lsr(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "NOP" => {
-      cpu.nop(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "ORA" => {
-      cpu.ora(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "PHA" => {
-      cpu.pha(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "PHP" => {
-      cpu.php(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "PLA" => {
-      cpu.pla(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "PLP" => {
-      cpu.plp(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "ROL" => {
-      cpu.rol(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "ROR" => {
-      cpu.ror(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "RTI" => {
-      cpu.rti(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "RTS" => {
-      cpu.rts(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SBC" => {
-      cpu.sbc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SEC" => {
-      cpu.sec(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SED" => {
-      cpu.sed(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SEI" => {
-      cpu.sei(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "STA" => {
-      cpu.sta(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "STX" => {
-      cpu.stx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "STY" => {
-      cpu.sty(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "TAX" => {
-      cpu.tax(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "TAY" => {
-      cpu.tay(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "TSX" => {
-      cpu.tsx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "TXA" => {
-      cpu.txa(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "TXS" => {
-      cpu.txs(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "TYA" => {
-      cpu.tya(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "ANC" => {
-      cpu.anc(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SAX" => {
-      cpu.sax(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "ARR" => {
-      cpu.arr(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "ASR" => {
-      cpu.asr(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "LXA" => {
-      cpu.lxa(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SHA" => {
-      cpu.sha(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SBX" => {
-      cpu.sbx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "DCP" => {
-      cpu.dcp(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "ISB" => {
-      cpu.isb(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "JAM" => {
-      cpu.jam(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "LAE" => {
-      cpu.lae(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "LAX" => {
-      cpu.lax(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "RLA" => {
-      cpu.rla(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "RRA" => {
-      cpu.rra(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SLO" => {
-      cpu.slo(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SRE" => {
-      cpu.sre(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SHX" => {
-      cpu.shx(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SHY" => {
-      cpu.shy(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "ANE" => {
-      cpu.ane(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    "SHS" => {
-      cpu.shs(&op.addressing_mode);
-      cpu.program_counter += op.bytes - 1
-    }
-
-    _ => {
-        todo!()
-    }
-  }
-}
\ No newline at end of file
+  (op.instruction)(cpu, &op.addressing_mode);
+  cpu.program_counter += op.bytes - 1
+}
@@ -1,3 +1,7 @@
+use once_cell::sync::Lazy;
+use std::fs::File;
+use std::io::Read;
+
 #[rustfmt::skip]
 
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
@@ -15,3 +19,196 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+// カラーエンファシス ($2001 bit5-7) で強調されていない色を暗くする割合
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+pub static mut PALETTE: Lazy<Palette> = Lazy::new(|| Palette::system());
+
+// 512色 (パレット番号 64 x エンファシス 8) のパレット
+#[derive(Clone)]
+pub struct Palette {
+    colors: Vec<(u8, u8, u8)>,
+}
+
+impl Palette {
+    pub fn system() -> Self {
+        Palette::with_emphasis(&SYSTEM_PALLETE)
+    }
+
+    // 64色のパレットから、エンファシスをかけた色を作る
+    fn with_emphasis(base: &[(u8, u8, u8)]) -> Self {
+        let mut colors = Vec::with_capacity(512);
+        for emphasis in 0..8 {
+            for color in 0..64 {
+                colors.push(emphasize(color as u8, base[color], emphasis));
+            }
+        }
+        Palette { colors: colors }
+    }
+
+    // .palファイル (64色 192バイト、またはエンファシス込みの512色 1536バイト)
+    pub fn from_bytes(raw: &[u8]) -> Result<Palette, String> {
+        if raw.len() != 192 && raw.len() != 1536 {
+            return Err(format!("Unsupported palette size: {}", raw.len()));
+        }
+        let colors: Vec<(u8, u8, u8)> = raw.chunks(3).map(|c| (c[0], c[1], c[2])).collect();
+        if colors.len() == 64 {
+            Ok(Palette::with_emphasis(&colors))
+        } else {
+            Ok(Palette { colors: colors })
+        }
+    }
+
+    // NTSCの信号をデコードしてパレットを作る
+    // see: https://www.nesdev.org/wiki/NTSC_video
+    pub fn ntsc(params: &NtscParams) -> Self {
+        let mut colors = Vec::with_capacity(512);
+        for pixel in 0..512 {
+            let (y, i, q) = ntsc_yiq(pixel as u16);
+            colors.push(yiq_to_rgb(y, i, q, params));
+        }
+        Palette { colors: colors }
+    }
+
+    // パレット番号(0x00-0x3F)とエンファシス(bit0: 赤, bit1: 緑, bit2: 青)から色を求める
+    pub fn color(&self, color: u8, emphasis: u8) -> (u8, u8, u8) {
+        self.colors[(emphasis as usize & 0b111) * 64 + (color & 0x3F) as usize]
+    }
+}
+
+pub fn load_palette(path: &str) -> Palette {
+    let mut f = File::open(path).expect("no palette file found");
+    let mut buffer = vec![];
+    f.read_to_end(&mut buffer).expect("unable to read palette");
+    Palette::from_bytes(&buffer).expect("load error")
+}
+
+fn emphasize(color: u8, rgb: (u8, u8, u8), emphasis: u8) -> (u8, u8, u8) {
+    // $xE, $xF の黒はエンファシスの影響を受けない
+    if emphasis == 0 || (color & 0x0F) >= 0x0E {
+        return rgb;
+    }
+
+    let mut rgb = [rgb.0 as f32, rgb.1 as f32, rgb.2 as f32];
+    for channel in 0..3 {
+        if emphasis & (1 << channel) != 0 {
+            for other in 0..3 {
+                if other != channel {
+                    rgb[other] *= EMPHASIS_ATTENUATION;
+                }
+            }
+        }
+    }
+    (rgb[0] as u8, rgb[1] as u8, rgb[2] as u8)
+}
+
+// NTSCデコードの調整値
+pub struct NtscParams {
+    pub hue: f32, // 度
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    pub gamma: f32,
+}
+
+impl NtscParams {
+    pub fn new() -> Self {
+        NtscParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 1.0,
+        }
+    }
+
+    // "hue=10,sat=1.2,contrast=1,bright=0,gamma=2.2" の形式。書かなかった値はデフォルトのまま
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut params = NtscParams::new();
+        for item in s.split(',').filter(|item| !item.is_empty()) {
+            let (key, value) = item.split_once('=')?;
+            let value: f32 = value.parse().ok()?;
+            match key {
+                "hue" => params.hue = value,
+                "sat" | "saturation" => params.saturation = value,
+                "contrast" => params.contrast = value,
+                "bright" | "brightness" => params.brightness = value,
+                "gamma" => params.gamma = value,
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+// 信号の電圧 (シンク基準)
+const NTSC_BLACK: f32 = 0.518;
+const NTSC_WHITE: f32 = 1.962;
+const NTSC_LEVELS_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const NTSC_LEVELS_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+// カラーバーストとの位相差 (調整値)
+const NTSC_PHASE_OFFSET: f32 = 3.9;
+
+// 1ドット分の信号 (12位相)
+// pixel: eee ll cccc (エンファシス, 明るさ, 色相)
+pub fn ntsc_signal(pixel: u16, phase: usize) -> f32 {
+    let in_color_phase = |color: usize| (color + phase) % 12 < 6;
+
+    let color = (pixel & 0x0F) as usize;
+    let mut level = ((pixel >> 4) & 0b11) as usize;
+    let emphasis = (pixel >> 6) & 0b111;
+    if color > 13 {
+        level = 1;
+    }
+    let mut low = NTSC_LEVELS_LOW[level];
+    let mut high = NTSC_LEVELS_HIGH[level];
+    if color == 0 {
+        low = high;
+    }
+    if color > 12 {
+        high = low;
+    }
+
+    let mut signal = if in_color_phase(color) { high } else { low };
+    if (emphasis & 0b001 != 0 && in_color_phase(0))
+        || (emphasis & 0b010 != 0 && in_color_phase(4))
+        || (emphasis & 0b100 != 0 && in_color_phase(8))
+    {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+    (signal - NTSC_BLACK) / (NTSC_WHITE - NTSC_BLACK)
+}
+
+// 位相ごとのI, Qの復調に使う (cos, sin)
+pub fn ntsc_carrier(phase: usize) -> (f32, f32) {
+    let angle = std::f32::consts::PI * (phase as f32 + NTSC_PHASE_OFFSET) / 6.0;
+    (angle.cos(), angle.sin())
+}
+
+fn ntsc_yiq(pixel: u16) -> (f32, f32, f32) {
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let signal = ntsc_signal(pixel, phase);
+        let (cos, sin) = ntsc_carrier(phase);
+        y += signal;
+        i += signal * cos;
+        q += signal * sin;
+    }
+    // 復調すると振幅が半分になるので、2倍する
+    (y / 12.0, i / 12.0 * 2.0, q / 12.0 * 2.0)
+}
+
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32, params: &NtscParams) -> (u8, u8, u8) {
+    let hue = params.hue.to_radians();
+    let (i, q) = (i * hue.cos() - q * hue.sin(), i * hue.sin() + q * hue.cos());
+    let y = y * params.contrast + params.brightness;
+    let i = i * params.saturation;
+    let q = q * params.saturation;
+
+    let r = y + 0.946882 * i + 0.623557 * q;
+    let g = y - 0.274788 * i - 0.635691 * q;
+    let b = y - 1.108545 * i + 1.709007 * q;
+    let gamma = |v: f32| (v.max(0.0).min(1.0).powf(1.0 / params.gamma) * 255.0) as u8;
+    (gamma(r), gamma(g), gamma(b))
+}
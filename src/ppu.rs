@@ -1,71 +1,189 @@
 use bitflags::bitflags;
 use log::{debug, info, trace};
 
-use crate::frame::Frame;
-use crate::mapper::Mapper;
+use crate::frame::{Frame, Layer};
+use crate::mapper::{Mapper, NameTable};
 use crate::palette;
-use crate::render::{self, render, sprite_palette};
-use crate::{cpu::IN_TRACE, rom::Mirroring, MAPPER};
+use crate::render;
+use crate::rom::Region;
+use crate::{cpu::IN_TRACE, MAPPER, RAM_INIT, REGION};
+
+// I/Oラッチの値が消えるまでのドット数 (約600ms)
+const IO_LATCH_DECAY: usize = 3_200_000;
 
 pub struct NesPPU {
     pub palette_table: [u8; 32],
-    pub vram: [u8; 2048],
+    // 0x000-0x7FF: PPU内蔵VRAM, 0x800-0xFFF: カートリッジ上の追加VRAM (4画面用)
+    pub vram: [u8; 4096],
 
     pub oam_addr: u8,
     pub oam_data: [u8; 256],
 
-    addr: AddrRegister,        // 0x2006 (0x2007)
+    // Scroll 0x2005, Addr 0x2006 (0x2007)
+    addr: LoopyRegister,
     pub ctrl: ControlRegister, // 0x2000
     internal_data_buf: u8,
 
     // Mask 0x2001
-    mask: MaskRegister,
+    pub mask: MaskRegister,
 
     // Status 0x2002
     status: StatusRegister,
 
-    // Scroll 0x2005
-    pub scroll: ScrollRegister,
-
+    // 1フレーム 341ドット x 262ライン (0-239: 表示, 240: ポストレンダー, 241-260: VBlank, 261: プリレンダー)
+    // PAL, Dendyは312ライン
+    region: Region,
     cycles: usize,
     scanline: usize,
-    pub nmi_interrupt: Option<i32>,
-    pub clear_nmi_interrupt: bool,
-
-    // 描画中にパレットテーブルを書き換えることが可能なので、その対応。
-    // 書き込まれた時点でのscanlineとその時のパレットのスナップショットを持っておき、
-    // レンダリング時に、その履歴を参照して描画することで実現。
-    pub scanline_palette_indexes: Vec<usize>,
-    pub scanline_palette_tables: Vec<[u8; 32]>,
+    total_cycles: usize,
+    odd_frame: bool,
+
+    // PPUのI/Oラッチ (オープンバス)。最後に読み書きした値が残り、時間が経つと0に戻る。
+    // see: https://www.nesdev.org/wiki/Open_bus_behavior#PPU_open_bus
+    io_latch: u8,
+    io_latch_refreshed: [usize; 8],
+    // VBlankフラグが立つ直前に$2002が読まれた
+    suppress_vblank: bool,
+    // VBlankフラグが立ったドット (total_cycles)
+    vblank_started: usize,
+    // $2002の読み込みでフラグは下がったが、CPUはその前にNMIを見ている
+    nmi_before_read: bool,
+    // 電源投入やリセットの後、最初のVBlankが終わるまでは$2000/$2001/$2005/$2006への書き込みを無視する
+    ignore_writes: bool,
+
+    // BGのフェッチ結果 (次のタイル)
+    bg_next_tile: u8,
+    bg_next_attr: u8,
+    bg_next_lo: u8,
+    bg_next_hi: u8,
+    // BGのシフトレジスタ (上位8bitが描画中のタイル)
+    bg_shift_lo: u16,
+    bg_shift_hi: u16,
+    bg_attr_shift_lo: u16,
+    bg_attr_shift_hi: u16,
+
+    // 次のラインに表示するスプライトのOAM番号
+    line_sprite_indexes: Vec<usize>,
+    // 次のラインに表示するスプライト (フェッチ中)
+    next_line_sprites: Vec<LineSprite>,
+    // 現在のラインに表示するスプライト
+    line_sprites: Vec<LineSprite>,
+
+    // 1ラインに9個以上のスプライトを表示する (実機では8個まで)
+    pub remove_sprite_limit: bool,
+}
+
+// 1ライン分のスプライトのパターン
+#[derive(Debug, Clone, Copy)]
+struct LineSprite {
+    x: u8,
+    attr: u8,
+    lo: u8,
+    hi: u8,
+    oam_index: u8,
 }
 
 impl NesPPU {
     pub fn new() -> Self {
         NesPPU {
-            vram: [0; 2048],
+            vram: [0; 4096],
             oam_data: [0; 64 * 4],
             oam_addr: 0,
             palette_table: [0; 32],
-            addr: AddrRegister::new(),
+            addr: LoopyRegister::new(),
             ctrl: ControlRegister::new(),
             status: StatusRegister::new(),
             mask: MaskRegister::new(),
-            scroll: ScrollRegister::new(),
             internal_data_buf: 0,
+            region: unsafe { REGION },
             cycles: 0,
             scanline: 0,
-            nmi_interrupt: None,
-            clear_nmi_interrupt: false,
-            scanline_palette_indexes: vec![],
-            scanline_palette_tables: vec![],
+            total_cycles: 0,
+            odd_frame: false,
+            io_latch: 0,
+            io_latch_refreshed: [0; 8],
+            suppress_vblank: false,
+            vblank_started: 0,
+            nmi_before_read: false,
+            ignore_writes: false,
+            bg_next_tile: 0,
+            bg_next_attr: 0,
+            bg_next_lo: 0,
+            bg_next_hi: 0,
+            bg_shift_lo: 0,
+            bg_shift_hi: 0,
+            bg_attr_shift_lo: 0,
+            bg_attr_shift_hi: 0,
+            line_sprite_indexes: vec![],
+            next_line_sprites: vec![],
+            line_sprites: vec![],
+            remove_sprite_limit: false,
+        }
+    }
+
+    // 電源投入。VBlankとスプライトオーバーフローのフラグは立っていることが多い
+    // see: https://www.nesdev.org/wiki/PPU_power_up_state
+    pub fn power_on(&mut self) {
+        *self = NesPPU {
+            remove_sprite_limit: self.remove_sprite_limit,
+            ..NesPPU::new()
+        };
+        // VRAM, OAM, パレットの中身は電源投入時のRAMの設定に従う
+        unsafe {
+            RAM_INIT.fill(&mut self.vram);
+            RAM_INIT.fill(&mut self.oam_data);
+            RAM_INIT.fill(&mut self.palette_table);
+        }
+        for color in self.palette_table.iter_mut() {
+            *color &= 0x3F;
+        }
+        self.status.set_vblank_status(true);
+        self.status.set_sprite_overflow(true);
+        self.ignore_writes = true;
+    }
+
+    // リセット。$2000, $2001, $2005/$2006 のラッチ, $2007 の読み込みバッファがクリアされる
+    // (vはクリアされない)
+    pub fn reset(&mut self) {
+        self.ctrl.update(0);
+        self.mask.update(0);
+        self.addr.reset();
+        self.internal_data_buf = 0;
+        self.odd_frame = false;
+        self.ignore_writes = true;
+    }
+
+    // ラッチのうち、maskのビットを更新する
+    fn refresh_io_latch(&mut self, value: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (value & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.io_latch_refreshed[bit] = self.total_cycles;
+            }
         }
     }
 
+    pub fn read_open_bus(&mut self) -> u8 {
+        for bit in 0..8 {
+            if self.total_cycles - self.io_latch_refreshed[bit] > IO_LATCH_DECAY {
+                self.io_latch &= !(1 << bit);
+            }
+        }
+        self.io_latch
+    }
+
     pub fn write_to_ppu_addr(&mut self, value: u8) {
-        self.addr.update(value);
+        self.refresh_io_latch(value, 0xFF);
+        if self.ignore_writes {
+            return;
+        }
+        if self.addr.write_addr(value) {
+            unsafe { MAPPER.ppu_bus_address(self.addr.get(), self.total_cycles) };
+        }
     }
 
     pub fn write_to_data(&mut self, value: u8) {
+        self.refresh_io_latch(value, 0xFF);
         let addr = self.addr.get();
         if !unsafe { IN_TRACE } {
             self.increment_vram_addr();
@@ -83,22 +201,12 @@ impl NesPPU {
                 }
             }
             0x2000..=0x2FFF => {
-                trace!(
-                    "WRITE PPU_VRAM {:04X} {:02X} => ({:02X})",
-                    addr,
-                    self.mirror_vram_addr(addr) as usize,
-                    value
-                );
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
+                trace!("WRITE PPU_VRAM {:04X} => ({:02X})", addr, value);
+                self.write_vram(addr, value);
             }
             0x3000..=0x3EFF => {
-                trace!(
-                    "WRITE PPU_VRAM MIRROR {:04X} {:02X} => ({:02X})",
-                    addr,
-                    self.mirror_vram_addr(addr) as usize,
-                    value
-                );
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
+                trace!("WRITE PPU_VRAM MIRROR {:04X} => ({:02X})", addr, value);
+                self.write_vram(addr, value);
             }
             0x3F00..=0x3F1F => {
                 debug!(
@@ -126,42 +234,11 @@ impl NesPPU {
 
     fn write_palette_table(&mut self, addr: u16, value: u8) {
         let addr = self.mirror_palette_addr(addr) as usize;
-
-        // palette_tableには最新情報を入れておく。
         self.palette_table[addr] = value;
-
-        let scanline = self.scanline;
-        let last_scanline = self.scanline_palette_indexes.last().unwrap_or(&0);
-        if *last_scanline != scanline {
-            self.scanline_palette_indexes.push(scanline);
-            self.scanline_palette_tables
-                .push(self.palette_table.clone());
-        } else {
-            self.scanline_palette_tables.pop();
-            self.scanline_palette_tables
-                .push(self.palette_table.clone());
-        }
     }
 
-    fn clear_palette_table_histories(&mut self) {
-        self.scanline_palette_indexes = vec![];
-        self.scanline_palette_tables = vec![];
-    }
-
-    pub fn read_palette_table(&self, scanline: usize) -> &[u8; 32] {
-        if self.scanline_palette_indexes.is_empty() {
-            return &self.palette_table;
-        }
-
-        let mut index = 0;
-        for (i, s) in self.scanline_palette_indexes.iter().enumerate() {
-            if *s > scanline {
-                break;
-            }
-            index = i
-        }
-        let table = &self.scanline_palette_tables[index];
-        table
+    pub fn read_palette_table(&self, addr: usize) -> u8 {
+        self.palette_table[self.mirror_palette_addr(addr as u16) as usize]
     }
 
     fn mirror_palette_addr(&self, addr: u16) -> u16 {
@@ -177,68 +254,121 @@ impl NesPPU {
     }
 
     pub fn write_to_ctrl(&mut self, value: u8) {
-        let before_nmi_status = self.ctrl.generate_vblank_nmi();
-        self.ctrl.update(value);
-        if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
-            self.nmi_interrupt = Some(1);
+        self.refresh_io_latch(value, 0xFF);
+        if self.ignore_writes {
+            return;
         }
+        self.ctrl.update(value);
+        self.addr.write_name_table(value);
     }
 
-    pub fn read_ctrl(&self) -> u8 {
-        self.ctrl.bits()
-    }
-
-    pub fn read_mask(&self) -> u8 {
-        self.mask.bits()
+    // NMIの出力。VBlank中にNMIを有効にすると、ここが立ち上がってNMIが発生する。
+    // 立ち上がりの検出はCPUがサイクルごとに行う。
+    pub fn nmi_line(&mut self) -> bool {
+        let nmi_before_read = std::mem::take(&mut self.nmi_before_read);
+        (self.status.is_in_vblank() && self.ctrl.generate_vblank_nmi()) || nmi_before_read
     }
 
     pub fn read_status(&mut self) -> u8 {
+        // 下位5bitはオープンバス
+        let bits = (self.status.bits() & 0xE0) | (self.read_open_bus() & 0x1F);
         if unsafe { IN_TRACE } {
-            self.status.bits()
-        } else {
-            self.scroll.reset();
-            self.addr.reset_latch();
-            let bits = self.status.bits();
-            self.status.reset_vblank_status();
-            self.clear_nmi_interrupt = true;
-            bits
+            return bits;
+        }
+        self.addr.reset_latch();
+        self.refresh_io_latch(bits, 0xE0);
+
+        // see: https://www.nesdev.org/wiki/PPU_frame_timing#VBL_Flag_Timing
+        // VBlankフラグが立つ1ドット前に読むと、フラグは0のまま立たず、NMIも発生しない
+        if self.is_just_before_vblank() {
+            self.suppress_vblank = true;
         }
+        // フラグが立ったドットか、その次のドットで読むと、フラグは読めるが
+        // CPUがNMIを見る前に下がるので、NMIは発生しない。
+        // 2ドット以上たっていれば、CPUは (このサイクルの途中で) NMIを見ている。
+        // CPUが割り込みを見るのはサイクルの終わり (読み込みの後) なので、ここで覚えておく
+        if self.status.is_in_vblank()
+            && self.ctrl.generate_vblank_nmi()
+            && self.total_cycles - self.vblank_started > 2
+        {
+            self.nmi_before_read = true;
+        }
+        self.status.reset_vblank_status();
+        bits
+    }
+
+    // 次のドットでVBlankフラグが立つか (CPUは、PPUを進めてから読む)
+    fn is_just_before_vblank(&self) -> bool {
+        self.scanline == self.region.vblank_line() && self.cycles == 1
+    }
+
+    // テスト用: 次に処理するドットを決める
+    #[cfg(test)]
+    pub fn set_position(&mut self, scanline: usize, cycles: usize) {
+        self.scanline = scanline;
+        self.cycles = cycles;
     }
 
     pub fn write_to_status(&mut self, value: u8) {
-        self.status.update(value);
+        // 読み込み専用。ラッチのみ更新される
+        self.refresh_io_latch(value, 0xFF);
     }
 
     pub fn write_to_mask(&mut self, value: u8) {
+        self.refresh_io_latch(value, 0xFF);
+        if self.ignore_writes {
+            return;
+        }
         self.mask.update(value);
     }
 
     pub fn write_to_oam_addr(&mut self, value: u8) {
+        self.refresh_io_latch(value, 0xFF);
         self.oam_addr = value;
     }
 
     pub fn write_to_oam_data(&mut self, value: u8) {
+        self.refresh_io_latch(value, 0xFF);
         debug!("OAM: {:04X} => {:02X}", self.oam_addr, value);
         self.oam_data[self.oam_addr as usize] = value;
         self.oam_addr = self.oam_addr.wrapping_add(1)
     }
 
-    pub fn read_oam_data(&self) -> u8 {
-        self.oam_data[self.oam_addr as usize]
-    }
-
-    pub fn write_to_oam_dma(&mut self, values: [u8; 256]) {
-        debug!("OAM DMA: ADDR:{:02X}", self.oam_addr);
-        debug!("{:?}", values);
-        self.oam_data = values;
+    pub fn read_oam_data(&mut self) -> u8 {
+        // レンダリング中、1-64ドットはセカンダリOAMの初期化中なので$FFが読める
+        let value =
+            if self.is_rendering() && self.is_render_line() && (1..=64).contains(&self.cycles) {
+                0xFF
+            } else if self.oam_addr & 0b11 == 2 {
+                // 属性のbit2-4は存在しない
+                self.oam_data[self.oam_addr as usize] & 0xE3
+            } else {
+                self.oam_data[self.oam_addr as usize]
+            };
+        if !unsafe { IN_TRACE } {
+            self.refresh_io_latch(value, 0xFF);
+        }
+        value
     }
 
     pub fn write_to_scroll(&mut self, value: u8) {
-        self.scroll.set(value);
+        self.refresh_io_latch(value, 0xFF);
+        if self.ignore_writes {
+            return;
+        }
+        self.addr.write_scroll(value);
     }
 
     fn increment_vram_addr(&mut self) {
+        if self.is_rendering() && self.is_render_line() {
+            // レンダリング中の$2007アクセスでは、coarse XとYが同時にインクリメントされる
+            self.addr.increment_coarse_x();
+            self.addr.increment_y();
+            return;
+        }
         self.addr.increment(self.ctrl.vram_addr_increment());
+        // レンダリング外では、インクリメント後のアドレスがPPUのアドレスバスに出る
+        unsafe { MAPPER.ppu_bus_address(self.addr.get(), self.total_cycles) };
     }
 
     pub fn read_data(&mut self) -> u8 {
@@ -248,14 +378,14 @@ impl NesPPU {
         }
         debug!("READ PPU: {:04X}", addr);
 
-        match addr {
+        let result = match addr {
             0..=0x1FFF => {
                 if unsafe { IN_TRACE } {
                     self.internal_data_buf
                 } else {
                     let result = self.internal_data_buf;
 
-                    self.internal_data_buf = unsafe { MAPPER.read_chr_rom(addr) };
+                    self.internal_data_buf = unsafe { MAPPER.fetch_chr(addr) };
                     result
                 }
             }
@@ -264,7 +394,7 @@ impl NesPPU {
                     self.internal_data_buf
                 } else {
                     let result = self.internal_data_buf;
-                    self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                    self.internal_data_buf = self.read_vram(addr);
                     result
                 }
             }
@@ -273,166 +403,552 @@ impl NesPPU {
                     self.internal_data_buf
                 } else {
                     let result = self.internal_data_buf;
-                    self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                    self.internal_data_buf = self.read_vram(addr);
                     result
                 }
             }
             0x3F00..=0x3FFF => {
+                // パレットはバッファを通さずに読める。上位2bitはオープンバス。
+                let mut color = self.palette_table[self.mirror_palette_addr(addr) as usize] & 0x3F;
+                if self.mask.is_greyscale() {
+                    color &= 0x30;
+                }
+                let result = color | (self.read_open_bus() & 0xC0);
                 if unsafe { IN_TRACE } {
-                    self.internal_data_buf
-                } else {
-                    self.internal_data_buf =
-                        self.palette_table[self.mirror_palette_addr(addr) as usize];
-                    self.internal_data_buf
+                    return result;
                 }
+                // バッファには、パレットの下にあるネームテーブルが読み込まれる
+                self.internal_data_buf = self.read_vram(addr - 0x1000);
+                self.refresh_io_latch(result, 0x3F);
+                return result;
             }
             _ => panic!("unexpected access to mirrored space {}", addr),
+        };
+        if !unsafe { IN_TRACE } {
+            self.refresh_io_latch(result, 0xFF);
         }
+        result
     }
 
-    pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
-        let mirrored_vram = addr & 0b10_1111_1111_1111;
-        let vram_index = mirrored_vram - 0x2000;
-        let name_table = vram_index / 0x400;
-        let mirroring = unsafe { MAPPER.mirroring() };
-        match (&mirroring, name_table) {
-            (Mirroring::VERTICAL, 2) => vram_index - 0x800,
-            (Mirroring::VERTICAL, 3) => vram_index - 0x800,
-            (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
-            _ => vram_index,
+    pub fn read_vram(&self, addr: u16) -> u8 {
+        let name_table = ((addr & 0x0FFF) / 0x400) as usize;
+        let offset = addr & 0x03FF;
+        match unsafe { MAPPER.name_table(name_table) } {
+            NameTable::Vram(page) => self.vram[page * 0x400 + offset as usize],
+            NameTable::ChrRom(bank) => unsafe { MAPPER.read_chr_name_table(bank, offset) },
+        }
+    }
+
+    fn write_vram(&mut self, addr: u16, value: u8) {
+        let name_table = ((addr & 0x0FFF) / 0x400) as usize;
+        let offset = addr & 0x03FF;
+        match unsafe { MAPPER.name_table(name_table) } {
+            NameTable::Vram(page) => self.vram[page * 0x400 + offset as usize] = value,
+            // CHR ROMへの書き込みは無視
+            NameTable::ChrRom(_) => {}
         }
     }
 
     pub fn tick(&mut self, cycles: u8, frame: &mut Frame) -> bool {
-        self.cycles += cycles as usize;
-        if self.cycles >= 341 {
-            if self.is_sprite_zero_hit(self.cycles) {
-                self.status.set_sprite_zero_hit(true);
+        let mut frame_end = false;
+        for _ in 0..cycles {
+            if self.tick_dot(frame) {
+                frame_end = true;
             }
+        }
+        frame_end
+    }
+
+    fn is_rendering(&self) -> bool {
+        self.mask.show_background() || self.mask.show_sprites()
+    }
 
-            self.cycles = self.cycles - 341;
+    fn pre_render_line(&self) -> usize {
+        self.region.scanlines() - 1
+    }
+
+    // 表示ラインとプリレンダーラインではフェッチを行う
+    fn is_render_line(&self) -> bool {
+        self.scanline < 240 || self.scanline == self.pre_render_line()
+    }
+
+    // 1ドット分の処理
+    // see: https://www.nesdev.org/wiki/PPU_rendering
+    fn tick_dot(&mut self, frame: &mut Frame) -> bool {
+        let dot = self.cycles;
+        let render_line = self.is_render_line();
+
+        // VBlankの開始と終了は、ラインの1ドット目
+        if dot == 1 && self.scanline == self.region.vblank_line() {
+            self.start_vblank();
+        }
+        if dot == 1 && self.scanline == self.pre_render_line() {
+            self.status.set_sprite_zero_hit(false);
+            self.status.set_sprite_overflow(false);
+            self.status.reset_vblank_status();
+            self.ignore_writes = false;
+        }
+
+        if render_line {
+            self.render_dot(frame, dot);
+        }
+
+        if render_line && self.is_rendering() {
+            self.fetch_background(dot);
+            self.fetch_sprites(dot);
+            if (257..=320).contains(&dot) {
+                // OAMADDR は、プリレンダリングおよび表示可能なスキャンラインのティック 257 ～ 320 (スプライト タイルの読み込み間隔) のそれぞれの間に 0 に設定されます。
+                self.oam_addr = 0;
+            }
+        }
+
+        self.cycles += 1;
+        self.total_cycles += 1;
+
+        // 奇数フレームでは、レンダリング中ならプリレンダーラインの最後のドット(340)を飛ばす (NTSCのみ)
+        if self.region == Region::NTSC
+            && self.scanline == self.pre_render_line()
+            && self.cycles == 340
+            && self.odd_frame
+            && self.is_rendering()
+        {
+            self.cycles = 341;
+        }
+
+        if self.cycles >= 341 {
+            self.cycles = 0;
             self.scanline += 1;
+            self.line_sprites = std::mem::take(&mut self.next_line_sprites);
 
-            if (self.scanline % 8) == 6 {
-                render(&self, frame, self.scanline + 2);
+            if self.scanline >= self.region.scanlines() {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+                return true;
             }
+        }
+        return false;
+    }
 
-            unsafe {
-                MAPPER.scanline(
-                    self.scanline,
-                    self.mask.show_background() || self.mask.show_sprites(),
-                );
+    fn start_vblank(&mut self) {
+        if self.suppress_vblank {
+            // 直前に$2002が読まれた
+            self.suppress_vblank = false;
+            return;
+        }
+        self.status.set_vblank_status(true);
+        self.vblank_started = self.total_cycles;
+    }
+
+    // パターンテーブルの読み込み。
+    // 実機のフェッチのタイミングで、アドレスをマッパーに通知する。
+    // (MMC3などはA12の立ち上がりでスキャンラインを数える)
+    fn fetch_pattern(&mut self, addr: u16) -> u8 {
+        unsafe {
+            MAPPER.ppu_bus_address(addr, self.total_cycles);
+            MAPPER.fetch_chr(addr)
+        }
+    }
+
+    // 表示ラインの1ドット分の描画。
+    // 背景のシフトはドットの描画より先に行う (x番目のドットは、x回シフトした値で描く)
+    fn render_dot(&mut self, frame: &mut Frame, dot: usize) {
+        if self.is_rendering() && (2..=257).contains(&dot) {
+            self.shift_background();
+        }
+        if self.scanline < 240 && (1..=256).contains(&dot) {
+            self.render_pixel(frame, dot - 1);
+        }
+    }
+
+    //   1-256, 321-336: 8ドットごとに NT, AT, パターン下位, パターン上位
+    //   (1-256 のシフトは render_dot で行う)
+    fn fetch_background(&mut self, dot: usize) {
+        if (322..=337).contains(&dot) {
+            self.shift_background();
+        }
+
+        if (1..=256).contains(&dot) || (321..=336).contains(&dot) {
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    let v = self.addr.get();
+                    self.bg_next_tile = self.read_vram(0x2000 | (v & 0x0FFF));
+                }
+                2 => {
+                    let v = self.addr.get();
+                    let attr_addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                    let mut attr = self.read_vram(attr_addr);
+                    if self.addr.coarse_y() & 0x02 != 0 {
+                        attr >>= 4;
+                    }
+                    if self.addr.coarse_x() & 0x02 != 0 {
+                        attr >>= 2;
+                    }
+                    self.bg_next_attr = attr & 0b11;
+                }
+                4 => {
+                    let addr = self.background_pattern_addr();
+                    self.bg_next_lo = self.fetch_pattern(addr);
+                }
+                6 => {
+                    let addr = self.background_pattern_addr() + 8;
+                    self.bg_next_hi = self.fetch_pattern(addr);
+                }
+                7 => self.addr.increment_coarse_x(),
+                _ => {}
             }
+        }
 
-            if self.scanline == 241 {
-                self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
-                if self.ctrl.generate_vblank_nmi() {
-                    // self.status.set_vblank_status(true);
-                    self.nmi_interrupt = Some(1);
+        if dot == 256 {
+            self.addr.increment_y();
+        }
+        if dot == 257 {
+            self.load_background_shifters();
+            self.addr.copy_horizontal();
+        }
+        if self.scanline == self.pre_render_line() && (280..=304).contains(&dot) {
+            self.addr.copy_vertical();
+        }
+    }
+
+    fn background_pattern_addr(&self) -> u16 {
+        self.ctrl.background_pattern_addr()
+            + (self.bg_next_tile as u16) * 16
+            + self.addr.fine_y() as u16
+    }
+
+    fn shift_background(&mut self) {
+        self.bg_shift_lo <<= 1;
+        self.bg_shift_hi <<= 1;
+        self.bg_attr_shift_lo <<= 1;
+        self.bg_attr_shift_hi <<= 1;
+    }
+
+    fn load_background_shifters(&mut self) {
+        self.bg_shift_lo = (self.bg_shift_lo & 0xFF00) | self.bg_next_lo as u16;
+        self.bg_shift_hi = (self.bg_shift_hi & 0xFF00) | self.bg_next_hi as u16;
+        let attr_lo = if self.bg_next_attr & 0x01 != 0 {
+            0xFF
+        } else {
+            0x00
+        };
+        let attr_hi = if self.bg_next_attr & 0x02 != 0 {
+            0xFF
+        } else {
+            0x00
+        };
+        self.bg_attr_shift_lo = (self.bg_attr_shift_lo & 0xFF00) | attr_lo;
+        self.bg_attr_shift_hi = (self.bg_attr_shift_hi & 0xFF00) | attr_hi;
+    }
+
+    //   257-320: 8ドットごとに NT, NT, パターン下位, パターン上位 (スプライト8個分)
+    fn fetch_sprites(&mut self, dot: usize) {
+        if !(257..=320).contains(&dot) {
+            return;
+        }
+        if dot == 257 {
+            self.evaluate_line_sprites();
+        }
+        let slot = (dot - 257) / 8;
+        match (dot - 257) % 8 {
+            4 => {
+                let addr = self.sprite_pattern_addr(slot);
+                let lo = self.fetch_pattern(addr);
+                self.push_line_sprite(slot, lo);
+            }
+            6 => {
+                let addr = self.sprite_pattern_addr(slot) + 8;
+                let hi = self.fetch_pattern(addr);
+                if let Some(sprite) = self.next_line_sprites.get_mut(slot) {
+                    sprite.hi = hi;
                 }
             }
+            _ => {}
+        }
+        if dot == 320 {
+            // スプライトの上限を外している場合は、9個目以降も読み込んでおく
+            for slot in 8..self.line_sprite_indexes.len() {
+                let addr = self.sprite_pattern_addr(slot);
+                let lo = unsafe { MAPPER.fetch_chr(addr) };
+                let hi = unsafe { MAPPER.fetch_chr(addr + 8) };
+                self.push_line_sprite(slot, lo);
+                self.next_line_sprites[slot].hi = hi;
+            }
+        }
+    }
 
-            if self.scanline >= 262 {
-                self.scanline = 0;
-                self.status.set_sprite_zero_hit(false);
-                self.status.reset_vblank_status();
-                self.nmi_interrupt = None;
-                self.clear_palette_table_histories();
-                return true;
+    fn push_line_sprite(&mut self, slot: usize, lo: u8) {
+        if let Some(&i) = self.line_sprite_indexes.get(slot) {
+            self.next_line_sprites.push(LineSprite {
+                x: self.oam_data[i + 3],
+                attr: self.oam_data[i + 2],
+                lo: lo,
+                hi: 0,
+                oam_index: (i / 4) as u8,
+            });
+        }
+    }
+
+    fn sprite_height(&self) -> usize {
+        if self.ctrl.is_sprite_8x16_mode() {
+            16
+        } else {
+            8
+        }
+    }
+
+    // スプライトのパターンのアドレス。空きスロットは$FFのタイルを読み込む。
+    fn sprite_pattern_addr(&self, slot: usize) -> u16 {
+        let (tile_idx, row) = match self.line_sprite_indexes.get(slot) {
+            Some(&i) => {
+                let tile_y = self.oam_data[i] as usize;
+                let attr = self.oam_data[i + 2];
+                let mut row = (self.scanline - tile_y) as u16;
+                let flip_vertical = (attr >> 7 & 1) == 1;
+                if flip_vertical {
+                    row = self.sprite_height() as u16 - 1 - row;
+                }
+                (self.oam_data[i + 1] as u16, row)
             }
+            None => (0xFF, 0),
+        };
 
-            if self.scanline == 257 {
-                // OAMADDR は、プリレンダリングおよび表示可能なスキャンラインのティック 257 ～ 320 (スプライト タイルの読み込み間隔) のそれぞれの間に 0 に設定されます。
-                self.oam_addr = 0;
+        if self.ctrl.is_sprite_8x16_mode() {
+            let bank = if (tile_idx & 0x01) == 0 { 0 } else { 0x1000 };
+            bank + ((tile_idx & 0xFE) + row / 8) * 16 + row % 8
+        } else {
+            self.ctrl.sprite_pattern_addr() + tile_idx * 16 + row
+        }
+    }
+
+    // 次のラインに表示するスプライトを選ぶ。(OAMのY座標の次のラインから表示される)
+    fn evaluate_line_sprites(&mut self) {
+        self.line_sprite_indexes.clear();
+        self.next_line_sprites.clear();
+        if self.scanline == self.pre_render_line() {
+            return;
+        }
+        // セカンダリOAMへのコピー (1ラインに8個まで)
+        let mut n = 0;
+        while n < 64 && self.line_sprite_indexes.len() < 8 {
+            if self.is_sprite_in_line(self.oam_data[n * 4]) {
+                self.line_sprite_indexes.push(n * 4);
             }
+            n += 1;
         }
-        return false;
+
+        // 8個見つかった次のスプライトからは、オーバーフローの判定。
+        // 実機のバグで、判定するバイトがY座標からずれていく (n と同時に m もインクリメントされる)
+        // see: https://www.nesdev.org/wiki/PPU_sprite_evaluation
+        let mut m = 0;
+        while n < 64 {
+            if self.is_sprite_in_line(self.oam_data[n * 4 + m]) {
+                self.status.set_sprite_overflow(true);
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0b11;
+        }
+
+        if self.remove_sprite_limit && self.line_sprite_indexes.len() == 8 {
+            // チラつき防止のため、9個目以降のスプライトも表示する (オーバーフローのフラグはそのまま)
+            // ずれた判定で見逃したスプライトもあるので、8個目の次から探し直す
+            let start = self.line_sprite_indexes[7] + 4;
+            for i in (start..self.oam_data.len()).step_by(4) {
+                if self.is_sprite_in_line(self.oam_data[i]) {
+                    self.line_sprite_indexes.push(i);
+                }
+            }
+        }
+    }
+
+    fn is_sprite_in_line(&self, y: u8) -> bool {
+        let y = y as usize;
+        self.scanline >= y && self.scanline < y + self.sprite_height()
     }
 
-    fn is_sprite_zero_hit(&self, cycle: usize) -> bool {
-        let y = self.oam_data[0] as usize;
-        let tile_idx = self.oam_data[1] as u16;
-        let x = self.oam_data[3] as usize;
-
-        // タイル取得
-        let bank: u16 = self.ctrl.sprite_pattern_addr();
-        let start = bank + tile_idx * 16;
-        let mut tile: [u8; 16] = [0; 16];
-        for i in 0..=15 {
-            tile[i] = unsafe { MAPPER.read_chr_rom(start + i as u16) }
-        }
-
-        let cur = self.scanline as i32 - (y as i32);
-        if 0 <= cur && cur <= 7 && x <= cycle && self.mask.show_sprites() {
-            let line = self.scanline % 8;
-
-            let mut upper = tile[line];
-            let mut lower = tile[line + 8];
-            'ololo: for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                match value {
-                    0 => continue 'ololo, // skip coloring the pixel
-                    _ => return true,
+    fn render_pixel(&mut self, frame: &mut Frame, x: usize) {
+        let mut bg_value = 0;
+        let mut bg_palette = 0;
+        // 左端8ドットの表示は、$2001 bit1, bit2 で隠せる
+        if self.mask.show_background() && (x >= 8 || self.mask.show_background_in_left()) {
+            let bit = 15 - self.addr.fine_x() as u16;
+            bg_value = ((self.bg_shift_hi >> bit) & 1) << 1 | ((self.bg_shift_lo >> bit) & 1);
+            bg_palette =
+                ((self.bg_attr_shift_hi >> bit) & 1) << 1 | ((self.bg_attr_shift_lo >> bit) & 1);
+        }
+
+        let mut sprite: Option<(u8, u8, bool, u8)> = None;
+        if self.mask.show_sprites() && (x >= 8 || self.mask.show_sprites_in_left()) {
+            // OAMの番号が小さいものを手前に描画する
+            for s in self.line_sprites.iter() {
+                let offset = x as isize - s.x as isize;
+                if offset < 0 || offset > 7 {
+                    continue;
+                }
+                let flip_horizontal = (s.attr >> 6 & 1) == 1;
+                let shift = if flip_horizontal { offset } else { 7 - offset };
+                let value = ((s.hi >> shift) & 1) << 1 | ((s.lo >> shift) & 1);
+                if value != 0 {
+                    // スプライト0がセカンダリOAMの先頭にある場合のみ
+                    if s.oam_index == 0 && bg_value != 0 && self.is_sprite_zero_hit(x) {
+                        self.status.set_sprite_zero_hit(true);
+                    }
+                    // 背景の後ろに表示するかどうかは、一番手前の不透明なスプライトで決まる
+                    let behind_background = (s.attr >> 5 & 1) == 1;
+                    sprite = Some((value, s.attr & 0b11, behind_background, s.oam_index));
+                    break;
                 }
             }
         }
-        return false;
+
+        let (palette_addr, layer) = match sprite {
+            Some((_, _, true, _)) if bg_value != 0 => {
+                ((bg_palette * 4 + bg_value) as usize, Layer::Background)
+            }
+            Some((value, palette_idx, _, oam_index)) => (
+                0x10 + (palette_idx * 4 + value) as usize,
+                Layer::Sprite(oam_index),
+            ),
+            None if bg_value != 0 => ((bg_palette * 4 + bg_value) as usize, Layer::Background),
+            None => (0, Layer::Backdrop),
+        };
+        render::render_pixel(self, frame, x, self.scanline, palette_addr, layer);
+    }
+
+    // 不透明なBGとスプライト0が重なったドットで、ヒットするかどうか
+    // see: https://www.nesdev.org/wiki/PPU_OAM#Sprite_zero_hits
+    fn is_sprite_zero_hit(&self, x: usize) -> bool {
+        if x == 255 {
+            return false;
+        }
+        if x < 8 && (!self.mask.show_background_in_left() || !self.mask.show_sprites_in_left()) {
+            return false;
+        }
+        true
     }
 }
 
-pub struct AddrRegister {
-    value: (u8, u8),
-    hi_ptr: bool,
+// PPU内部のレジスタ v, t, x, w (loopy氏の資料より)
+// see: https://www.nesdev.org/wiki/PPU_scrolling
+//   v, t: yyy NN YYYYY XXXXX (fine Y, ネームテーブル, coarse Y, coarse X)
+pub struct LoopyRegister {
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
 }
 
-impl AddrRegister {
+impl LoopyRegister {
     pub fn new() -> Self {
-        AddrRegister {
-            value: (0, 0),
-            hi_ptr: true,
+        LoopyRegister {
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
         }
     }
 
-    fn set(&mut self, data: u16) {
-        self.value.0 = (data >> 8) as u8;
-        self.value.1 = (data & 0xFF) as u8;
+    // リセット (t, x, w をクリア)
+    pub fn reset(&mut self) {
+        self.t = 0;
+        self.x = 0;
+        self.w = false;
     }
 
-    pub fn update(&mut self, data: u8) {
-        if self.hi_ptr {
-            self.value.0 = data;
+    // $2000
+    pub fn write_name_table(&mut self, data: u8) {
+        self.t = (self.t & !0x0C00) | ((data as u16 & 0b11) << 10);
+    }
+
+    // $2005
+    pub fn write_scroll(&mut self, data: u8) {
+        if !self.w {
+            self.t = (self.t & !0x001F) | (data as u16 >> 3);
+            self.x = data & 0b111;
         } else {
-            self.value.1 = data;
+            self.t = (self.t & !0x73E0) | ((data as u16 & 0b111) << 12) | ((data as u16 >> 3) << 5);
         }
+        self.w = !self.w;
+    }
 
-        if self.get() > 0x3FFF {
-            self.set(self.get() & 0b11_1111_1111_1111);
+    // $2006 (2回目の書き込みでvが更新されたらtrue)
+    pub fn write_addr(&mut self, data: u8) -> bool {
+        self.w = !self.w;
+        if self.w {
+            self.t = (self.t & 0x00FF) | ((data as u16 & 0x3F) << 8);
+            false
+        } else {
+            self.t = (self.t & 0xFF00) | data as u16;
+            self.v = self.t;
+            true
         }
-        self.hi_ptr = !self.hi_ptr;
     }
 
     pub fn increment(&mut self, inc: u8) {
-        let lo = self.value.1;
-        self.value.1 = self.value.1.wrapping_add(inc);
-        if lo > self.value.1 {
-            self.value.0 = self.value.0.wrapping_add(1);
-        }
-        if self.get() > 0x3FFF {
-            self.set(self.get() & 0b11_1111_1111_1111);
-        }
+        self.v = self.v.wrapping_add(inc as u16) & 0x7FFF;
     }
 
     pub fn reset_latch(&mut self) {
-        self.hi_ptr = true;
+        self.w = false;
     }
 
     pub fn get(&self) -> u16 {
-        ((self.value.0 as u16) << 8) | (self.value.1 as u16)
+        self.v & 0x3FFF
+    }
+
+    pub fn coarse_x(&self) -> u16 {
+        self.v & 0x001F
+    }
+
+    pub fn coarse_y(&self) -> u16 {
+        (self.v >> 5) & 0x001F
+    }
+
+    pub fn fine_y(&self) -> u16 {
+        (self.v >> 12) & 0b111
+    }
+
+    pub fn fine_x(&self) -> u8 {
+        self.x
+    }
+
+    pub fn increment_coarse_x(&mut self) {
+        if self.coarse_x() == 31 {
+            // 右のネームテーブルへ
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    pub fn increment_y(&mut self) {
+        if self.fine_y() < 7 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut y = self.coarse_y();
+        if y == 29 {
+            // 下のネームテーブルへ
+            y = 0;
+            self.v ^= 0x0800;
+        } else if y == 31 {
+            // 属性テーブルの領域からは、ネームテーブルを切り替えずに戻る
+            y = 0;
+        } else {
+            y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (y << 5);
+    }
+
+    pub fn copy_horizontal(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    pub fn copy_vertical(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
     }
 }
 
@@ -489,18 +1005,6 @@ impl ControlRegister {
             0x1000
         }
     }
-
-    pub fn nametable_addr(&self) -> u16 {
-        match (
-            self.contains(ControlRegister::NAMETABLE2),
-            self.contains(ControlRegister::NAMETABLE1),
-        ) {
-            (false, false) => 0x2000,
-            (false, true) => 0x2400,
-            (true, false) => 0x2800,
-            (true, true) => 0x2C00,
-        }
-    }
 }
 
 bitflags! {
@@ -537,8 +1041,8 @@ impl StatusRegister {
         self.set(StatusRegister::SPRITE_ZERO_HIT, value)
     }
 
-    pub fn update(&mut self, data: u8) {
-        *self.0.bits_mut() = data;
+    pub fn set_sprite_overflow(&mut self, value: bool) {
+        self.set(StatusRegister::SPRITE_OVERFLOW, value)
     }
 }
 
@@ -568,36 +1072,202 @@ impl MaskRegister {
         self.contains(MaskRegister::SHOW_BACKGROUND)
     }
 
+    pub fn show_sprites_in_left(&self) -> bool {
+        self.contains(MaskRegister::SHOW_SPRITES_IN_LEFT)
+    }
+
+    pub fn show_background_in_left(&self) -> bool {
+        self.contains(MaskRegister::SHOW_BACKGROUND_IN_LEFT)
+    }
+
+    pub fn is_greyscale(&self) -> bool {
+        self.contains(MaskRegister::GREYSCALE)
+    }
+
+    // bit0: 赤, bit1: 緑, bit2: 青
+    pub fn emphasis(&self) -> u8 {
+        self.bits() >> 5
+    }
+
     pub fn update(&mut self, data: u8) {
         *self.0.bits_mut() = data;
     }
 }
 
-pub struct ScrollRegister {
-    pub scroll_x: u8,
-    pub scroll_y: u8,
-    write_x: bool,
-}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::insert_mapper;
+    use crate::rom::Rom;
+
+    #[test]
+    fn test_background_pixels_in_order() {
+        let mut ppu = NesPPU::new();
+        let mut frame = Frame::new();
+        ppu.mask.update(0b0000_1010);
+        ppu.palette_table[0..4].copy_from_slice(&[0x0F, 0x11, 0x21, 0x31]);
+        // プリフェッチ後の状態: 上位バイトに1つ目のタイル
+        //   下位 1100_1010, 上位 1010_0110 => 3, 1, 2, 0, 1, 2, 3, 0
+        ppu.bg_shift_lo = 0b1100_1010 << 8;
+        ppu.bg_shift_hi = 0b1010_0110 << 8;
+
+        // fine x = 0 なら、タイルの8ドットがそのまま並ぶ
+        for dot in 1..=8 {
+            ppu.render_dot(&mut frame, dot);
+        }
+        let pixels: Vec<u16> = (0..8).map(|x| frame.index(x, 0)).collect();
+        assert_eq!(pixels, vec![0x31, 0x11, 0x21, 0x0F, 0x11, 0x21, 0x31, 0x0F]);
+    }
 
-impl ScrollRegister {
-    pub fn new() -> Self {
-        ScrollRegister {
-            scroll_x: 0,
-            scroll_y: 0,
-            write_x: true,
+    #[test]
+    fn test_sprite_overflow_diagonal_scan() {
+        let mut ppu = NesPPU::new();
+        ppu.scanline = 10;
+        ppu.oam_data = [0xF0; 256];
+        // 0-7番がこのラインに8個
+        for n in 0..8 {
+            ppu.oam_data[n * 4] = 10;
         }
+        // 9番もこのラインにあるが、m = 1 なのでタイル番号 (0xF0) を見てしまい、実機では見逃す
+        ppu.oam_data[9 * 4] = 10;
+        ppu.evaluate_line_sprites();
+        assert_eq!(ppu.line_sprite_indexes.len(), 8);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+
+        // 逆に、Y座標が範囲外でも、ずれた先のバイトが範囲内ならオーバーフローになる
+        ppu.oam_data[9 * 4] = 0xF0;
+        ppu.oam_data[9 * 4 + 1] = 10;
+        ppu.evaluate_line_sprites();
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
     }
 
-    fn set(&mut self, data: u8) {
-        if self.write_x {
-            self.scroll_x = data;
-        } else {
-            self.scroll_y = data;
+    #[test]
+    fn test_sprite_zero_hit_edges() {
+        let mut ppu = NesPPU::new();
+        let mut frame = Frame::new();
+        // 背景はすべて不透明
+        ppu.bg_shift_lo = 0xFFFF;
+        let sprite_zero = |x: u8| LineSprite {
+            x: x,
+            attr: 0,
+            lo: 0xFF,
+            hi: 0,
+            oam_index: 0,
+        };
+        let hit = |ppu: &mut NesPPU, frame: &mut Frame, x: usize| {
+            ppu.status.set_sprite_zero_hit(false);
+            ppu.render_pixel(frame, x);
+            ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT)
+        };
+
+        // x = 255 では当たらない
+        ppu.mask.update(0b0001_1110);
+        ppu.line_sprites = vec![sprite_zero(248)];
+        assert!(hit(&mut ppu, &mut frame, 254));
+        assert!(!hit(&mut ppu, &mut frame, 255));
+
+        // 左端8ドットは、背景とスプライトの両方を表示しているときだけ
+        ppu.line_sprites = vec![sprite_zero(0)];
+        assert!(hit(&mut ppu, &mut frame, 0));
+        assert!(hit(&mut ppu, &mut frame, 7));
+        for mask in [0b0001_1100, 0b0001_1010] {
+            ppu.mask.update(mask);
+            assert!(!hit(&mut ppu, &mut frame, 0));
+            assert!(!hit(&mut ppu, &mut frame, 7));
         }
-        self.write_x = !self.write_x;
+        ppu.line_sprites = vec![sprite_zero(8)];
+        assert!(hit(&mut ppu, &mut frame, 8));
     }
 
-    pub fn reset(&mut self) {
-        self.write_x = true;
+    #[test]
+    fn test_pixel_layers() {
+        let mut ppu = NesPPU::new();
+        let mut frame = Frame::new();
+        // 赤のエンファシス
+        ppu.mask.update(0b0011_1110);
+        ppu.palette_table[0x00] = 0x0F;
+        ppu.palette_table[0x01] = 0x11;
+        ppu.palette_table[0x11] = 0x21;
+        let sprite = |x: u8, attr: u8, lo: u8, oam_index: u8| LineSprite {
+            x: x,
+            attr: attr,
+            lo: lo,
+            hi: 0,
+            oam_index: oam_index,
+        };
+        ppu.line_sprites = vec![
+            // 5番: 2ドット目だけ不透明
+            sprite(2, 0, 0b1000_0000, 5),
+            // 9番: 背景の後ろ
+            sprite(3, 0x20, 0xFF, 9),
+        ];
+        for x in 0..12 {
+            // 背景は0-3ドット目だけ不透明
+            ppu.bg_shift_lo = if x < 4 { 0x8000 } else { 0 };
+            ppu.render_pixel(&mut frame, x);
+        }
+
+        let pixel = |x| (frame.index(x, 0), frame.layer(x, 0));
+        assert_eq!(pixel(0), (0x51, Layer::Background));
+        assert_eq!(pixel(2), (0x61, Layer::Sprite(5)));
+        assert_eq!(pixel(3), (0x51, Layer::Background));
+        assert_eq!(pixel(5), (0x61, Layer::Sprite(9)));
+        assert_eq!(pixel(11), (0x4F, Layer::Backdrop));
+    }
+
+    #[test]
+    fn test_read_status() {
+        let mut ppu = NesPPU::new();
+        // 下位5bitはI/Oラッチ
+        ppu.refresh_io_latch(0xFF, 0xFF);
+        ppu.status.set_vblank_status(true);
+        assert_eq!(ppu.read_status(), 0x9F);
+        // 読むとVBlankフラグが下がる
+        assert_eq!(ppu.read_status(), 0x1F);
+        assert_eq!(ppu.read_open_bus(), 0x1F);
+    }
+
+    #[test]
+    fn test_read_oam_data() {
+        let mut ppu = NesPPU::new();
+        ppu.oam_data[0..4].copy_from_slice(&[0x10, 0x20, 0xFF, 0x30]);
+        ppu.oam_addr = 2;
+        // 属性のbit2-4は読めない
+        assert_eq!(ppu.read_oam_data(), 0xE3);
+        ppu.oam_addr = 1;
+        assert_eq!(ppu.read_oam_data(), 0x20);
+        assert_eq!(ppu.read_open_bus(), 0x20);
+
+        // レンダリング中の1-64ドットは$FF
+        ppu.mask.update(0b0001_1000);
+        ppu.set_position(0, 1);
+        assert_eq!(ppu.read_oam_data(), 0xFF);
+        ppu.set_position(0, 64);
+        assert_eq!(ppu.read_oam_data(), 0xFF);
+        ppu.set_position(0, 65);
+        assert_eq!(ppu.read_oam_data(), 0x20);
+        // VBlank中は読める
+        ppu.set_position(241, 10);
+        assert_eq!(ppu.read_oam_data(), 0x20);
+    }
+
+    #[test]
+    fn test_read_palette() {
+        let _mapper = insert_mapper(Rom::empty());
+        let mut ppu = NesPPU::new();
+        ppu.palette_table[1] = 0x21;
+        ppu.write_vram(0x2F01, 0x55);
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0x01);
+
+        // パレットはすぐに読める。上位2bitはI/Oラッチ
+        ppu.refresh_io_latch(0xC0, 0xFF);
+        assert_eq!(ppu.read_data(), 0xE1);
+        assert_eq!(ppu.read_open_bus(), 0xE1);
+
+        // バッファには下のネームテーブル ($2F01) が入っている
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0x55);
     }
 }
@@ -1,14 +1,137 @@
+use crate::gamedb;
+use log::info;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
+    ONE_SCREEN_LOWER,
+    ONE_SCREEN_UPPER,
     FOUR_SCREEN,
 }
 
+impl Mirroring {
+    // ネームテーブル(0: $2000, 1: $2400, 2: $2800, 3: $2C00)が参照するVRAMの1KBページ
+    pub fn vram_page(&self, name_table: usize) -> usize {
+        match self {
+            Mirroring::VERTICAL => name_table & 0x01,
+            Mirroring::HORIZONTAL => name_table >> 1,
+            Mirroring::ONE_SCREEN_LOWER => 0,
+            Mirroring::ONE_SCREEN_UPPER => 1,
+            // 2, 3はカートリッジ上の追加VRAM
+            Mirroring::FOUR_SCREEN => name_table,
+        }
+    }
+}
+
+// 地域ごとのタイミング
+// see: https://www.nesdev.org/wiki/Cycle_reference_chart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    NTSC,
+    PAL,
+    DENDY,
+}
+
+impl Region {
+    pub fn parse(name: &str) -> Option<Region> {
+        match name {
+            "ntsc" => Some(Region::NTSC),
+            "pal" => Some(Region::PAL),
+            "dendy" => Some(Region::DENDY),
+            _ => None,
+        }
+    }
+
+    pub fn cpu_clock(&self) -> f32 {
+        match self {
+            Region::NTSC => 1_789_772.5, // 1.78MHz
+            Region::PAL => 1_662_607.0,
+            Region::DENDY => 1_773_448.0,
+        }
+    }
+
+    // CPU 1サイクルあたりのPPUのドット数 (分子, 分母)。PALは3.2
+    pub fn ppu_dots_per_cpu_cycle(&self) -> (usize, usize) {
+        match self {
+            Region::PAL => (16, 5),
+            _ => (3, 1),
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::NTSC => 60.0988,
+            Region::PAL | Region::DENDY => 50.0070,
+        }
+    }
+
+    // 1フレームのライン数 (最後のラインがプリレンダーライン)
+    pub fn scanlines(&self) -> usize {
+        match self {
+            Region::NTSC => 262,
+            Region::PAL | Region::DENDY => 312,
+        }
+    }
+
+    // VBlankが始まるライン (Dendyはポストレンダーが51ライン続く)
+    pub fn vblank_line(&self) -> usize {
+        match self {
+            Region::NTSC | Region::PAL => 241,
+            Region::DENDY => 291,
+        }
+    }
+
+    // APUのフレームシーケンサーのステップ間隔 (CPUサイクル)
+    pub fn frame_counter_interval(&self) -> usize {
+        match self {
+            Region::PAL => 8313,
+            _ => 7457,
+        }
+    }
+
+    // Dendyのクローン (UA6527P) のAPUは、CPUのクロックはPALと同じだが、
+    // 周期の表はNTSCのもの (2A03) を使う。そのため音はNTSCより少し低くなる。
+    // see: https://www.nesdev.org/wiki/Dendy
+    pub fn noise_period(&self, index: u8) -> u16 {
+        match self {
+            Region::PAL => PAL_NOISE_TABLE[index as usize],
+            Region::NTSC | Region::DENDY => NTSC_NOISE_TABLE[index as usize],
+        }
+    }
+
+    pub fn dmc_period(&self, index: u8) -> u16 {
+        match self {
+            Region::PAL => PAL_DMC_TABLE[index as usize],
+            Region::NTSC | Region::DENDY => NTSC_DMC_TABLE[index as usize],
+        }
+    }
+}
+
+static NTSC_NOISE_TABLE: [u16; 16] = [
+    0x004, 0x008, 0x010, 0x020, 0x040, 0x060, 0x080, 0x0A0, 0x0CA, 0x0FE, 0x17C, 0x1FC, 0x2FA,
+    0x3F8, 0x7F2, 0xFE4,
+];
+
+static PAL_NOISE_TABLE: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+static NTSC_DMC_TABLE: [u16; 16] = [
+    0x1AC, 0x17C, 0x154, 0x140, 0x11E, 0x0FE, 0x0E2, 0x0D6, 0x0BE, 0x0A0, 0x08E, 0x080, 0x06A,
+    0x054, 0x048, 0x036,
+];
+
+static PAL_DMC_TABLE: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A]; // NES^Z
 const PRG_ROM_PAGE_SIZE: usize = 16 * 1024; // 16KiB
 const CHR_ROM_PAGE_SIZE: usize = 8 * 1024; // 8KiB
+const PRG_RAM_PAGE_SIZE: usize = 8 * 1024; // 8KiB
+#[derive(Clone)]
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
@@ -16,6 +139,12 @@ pub struct Rom {
     pub submapper: u8,
     pub screen_mirroring: Mirroring,
     pub is_chr_ram: bool,
+    pub prg_ram_size: usize,
+    // PRG RAMのサイズがわかっているか (NES2.0のヘッダー、iNES 1.0 のbyte8、ゲームのデータベース)
+    // falseの場合、prg_ram_sizeは推測 (8KB)
+    pub prg_ram_size_known: bool,
+    pub has_battery: bool,
+    pub region: Region,
 
     pub save_data: Vec<u8>,
     pub save_data_file: String,
@@ -28,12 +157,10 @@ impl Rom {
         }
 
         let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
-        let submapper = raw[8] & 0xF0 >> 4;
 
-        // let ines_ver = (raw[7] >> 2) & 0b11;
-        // if ines_ver != 0 {
-        //     return Err("NES2.0 format is not supported".to_string());
-        // }
+        // submapperはNES2.0のヘッダーにのみ存在する
+        let ines_ver = (raw[7] >> 2) & 0b11;
+        let submapper = if ines_ver == 2 { raw[8] >> 4 } else { 0 };
 
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b1 != 0;
@@ -43,6 +170,36 @@ impl Rom {
             (false, false) => Mirroring::HORIZONTAL,
         };
 
+        // 古いダンプは byte7-15 にゴミが入っていることがあるので、
+        // iNES 1.0 の byte8-10 は byte12-15 が0の場合のみ使う
+        let clean_header = raw[12..16].iter().all(|b| *b == 0);
+
+        // PRG RAMのサイズ (NES2.0: 揮発 + 不揮発, NES1.0: 8KB単位、0またはゴミの場合は8KB)
+        let (prg_ram_size, prg_ram_size_known) = if ines_ver == 2 {
+            let shift_size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+            (shift_size(raw[10] & 0x0F) + shift_size(raw[10] >> 4), true)
+        } else if clean_header && raw[8] != 0 {
+            (raw[8] as usize * PRG_RAM_PAGE_SIZE, true)
+        } else {
+            (PRG_RAM_PAGE_SIZE, false)
+        };
+        let has_battery = raw[6] & 0b10 != 0;
+
+        // NES2.0: byte12 (0: NTSC, 1: PAL, 2: 両対応, 3: Dendy)
+        // NES1.0: byte9 bit0, 非公式の byte10 bit0-1 (0: NTSC, 2: PAL, 1/3: 両対応)
+        // iNES 1.0 ではほとんど設定されていないので、ゲームのデータベースがあればそちらを優先する
+        let region = if ines_ver == 2 {
+            match raw[12] & 0b11 {
+                1 => Region::PAL,
+                3 => Region::DENDY,
+                _ => Region::NTSC,
+            }
+        } else if raw[9] & 0b1 != 0 || (clean_header && raw[10] & 0b11 == 2) {
+            Region::PAL
+        } else {
+            Region::NTSC
+        };
+
         let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
         let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
 
@@ -60,16 +217,64 @@ impl Rom {
             raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec()
         };
 
-        Ok(Rom {
+        let mut rom = Rom {
             prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
             chr_rom: chr_rom,
             mapper: mapper,
             submapper: submapper,
             screen_mirroring: screen_mirroring,
             is_chr_ram: chr_rom_size == 0,
+            prg_ram_size: prg_ram_size,
+            prg_ram_size_known: prg_ram_size_known,
+            has_battery: has_battery,
+            region: region,
             save_data: Vec::new(),
             save_data_file: String::from(""),
-        })
+        };
+        if ines_ver != 2 {
+            rom.apply_game_database();
+        }
+        Ok(rom)
+    }
+
+    // ゲームのデータベースのキー (ヘッダーを除いたPRG ROM + CHR ROMのCRC32)
+    pub fn crc32(&self) -> u32 {
+        let mut data = self.prg_rom.clone();
+        if !self.is_chr_ram {
+            data.extend_from_slice(&self.chr_rom);
+        }
+        gamedb::crc32(&data)
+    }
+
+    // iNES 1.0 のヘッダーにない情報を、ゲームのデータベースで補う
+    fn apply_game_database(&mut self) {
+        let crc = self.crc32();
+        let Some(game) = gamedb::lookup(crc) else {
+            return;
+        };
+        // ヘッダーと違うマッパーの情報は使わない
+        // (MMC1A (マッパー155) は、iNES 1.0 ではマッパー1として出回っている)
+        let mmc1a = self.mapper == 1 && game.mapper == 155;
+        if game.mapper != self.mapper as u16 && !mmc1a {
+            info!(
+                "game database: {:08X} is mapper {}, ignored",
+                crc, game.mapper
+            );
+            return;
+        }
+        info!(
+            "game database: {:08X} mapper={} submapper={} prg_ram={:?} region={:?}",
+            crc, game.mapper, game.submapper, game.prg_ram_size, game.region
+        );
+        self.mapper = game.mapper as u8;
+        self.submapper = game.submapper;
+        if let Some(size) = game.prg_ram_size {
+            self.prg_ram_size = size;
+            self.prg_ram_size_known = true;
+        }
+        if let Some(region) = game.region {
+            self.region = region;
+        }
     }
 
     pub fn empty() -> Self {
@@ -80,8 +285,48 @@ impl Rom {
             submapper: 0,
             screen_mirroring: Mirroring::VERTICAL,
             is_chr_ram: false,
+            prg_ram_size: 0,
+            prg_ram_size_known: false,
+            has_battery: false,
+            region: Region::NTSC,
             save_data: Vec::new(),
             save_data_file: String::from(""),
         };
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // iNES 1.0, MMC1, PRG ROM 32KB, CHR RAM。byte8-15 を指定する
+    fn ines1(header: [u8; 8]) -> Rom {
+        let mut raw = vec![b'N', b'E', b'S', 0x1A, 2, 0, 0x10, 0x00];
+        raw.extend_from_slice(&header);
+        raw.resize(16 + 2 * PRG_ROM_PAGE_SIZE, 0);
+        Rom::new(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_ines1_prg_ram_size() {
+        // byte8 が0なら8KB (推測)
+        let rom = ines1([0; 8]);
+        assert_eq!(
+            (rom.prg_ram_size, rom.prg_ram_size_known),
+            (8 * 1024, false)
+        );
+
+        let rom = ines1([2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            (rom.prg_ram_size, rom.prg_ram_size_known),
+            (16 * 1024, true)
+        );
+
+        // byte12-15 にゴミがあれば、byte8 も信用しない
+        let rom = ines1([2, 0, 0, 0, b'D', b'u', b'd', b'e']);
+        assert_eq!(
+            (rom.prg_ram_size, rom.prg_ram_size_known),
+            (8 * 1024, false)
+        );
+    }
+}